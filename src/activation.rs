@@ -0,0 +1,104 @@
+//! Listeners passed in by systemd socket activation, see `sd_listen_fds(3)`.
+//!
+//! The service manager passes `LISTEN_FDS` sockets starting at file
+//! descriptor 3, and sets `LISTEN_PID` to the pid of the process they are meant
+//! for. The variables are read once and left in the environment: child
+//! processes have another pid, so they don't mistake the sockets for their own,
+//! and plugins are started without them anyway.
+
+use log::*;
+
+use std::env;
+use std::net::TcpListener;
+use std::os::raw::c_int;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::sync::Mutex;
+
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The variables describing the activated sockets.
+pub(crate) const LISTEN_VARS: [&str; 3] = ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"];
+
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Activated listeners, taken by the servers using them.
+pub(crate) struct Listeners(Vec<Listener>);
+
+lazy_static::lazy_static! {
+    /// The listeners not taken yet, read from the environment on first use.
+    static ref LISTENERS: Mutex<Listeners> = Mutex::new(Listeners::from_vars(|key| env::var(key).ok(), LISTEN_FDS_START));
+}
+
+impl Listeners {
+    /// The listeners passed from file descriptor `start` on, as described
+    /// by the variables `var` looks up.
+    pub(crate) fn from_vars<F: Fn(&str) -> Option<String>>(var: F, start: RawFd) -> Self {
+        let pid = var("LISTEN_PID").and_then(|pid| pid.parse::<u32>().ok());
+        let count = var("LISTEN_FDS").and_then(|count| count.parse::<RawFd>().ok());
+        let count = match (pid, count) {
+            (Some(pid), Some(count)) if pid == std::process::id() => count,
+            _ => return Listeners(Vec::new()),
+        };
+        Listeners((start..start + count).filter_map(|fd| {
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+            let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            if unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) } != 0 {
+                warn!("Ignoring activated fd {}: {}", fd, std::io::Error::last_os_error());
+                return None;
+            }
+            match c_int::from(addr.ss_family) {
+                libc::AF_UNIX => Some(Listener::Unix(unsafe { UnixListener::from_raw_fd(fd) })),
+                libc::AF_INET | libc::AF_INET6 => Some(Listener::Tcp(unsafe { TcpListener::from_raw_fd(fd) })),
+                family => {
+                    warn!("Ignoring activated fd {} with address family {}", fd, family);
+                    None
+                },
+            }
+        }).collect())
+    }
+
+    fn take<T, F: Fn(Listener) -> Result<T, Listener>>(&mut self, f: F) -> Option<T> {
+        let mut found = None;
+        for listener in std::mem::take(&mut self.0) {
+            match found {
+                None => match f(listener) {
+                    Ok(taken) => found = Some(taken),
+                    Err(listener) => self.0.push(listener),
+                },
+                Some(_) => self.0.push(listener),
+            }
+        }
+        found
+    }
+
+    /// The first TCP socket not used yet.
+    pub(crate) fn take_tcp(&mut self) -> Option<TcpListener> {
+        self.take(|listener| match listener {
+            Listener::Tcp(listener) => Ok(listener),
+            other => Err(other),
+        })
+    }
+
+    /// The first Unix socket not used yet.
+    pub(crate) fn take_unix(&mut self) -> Option<UnixListener> {
+        self.take(|listener| match listener {
+            Listener::Unix(listener) => Ok(listener),
+            other => Err(other),
+        })
+    }
+}
+
+/// The first activated TCP socket not used yet.
+pub(crate) fn take_tcp() -> Option<TcpListener> {
+    LISTENERS.lock().unwrap().take_tcp()
+}
+
+/// The first activated Unix socket not used yet.
+pub(crate) fn take_unix() -> Option<UnixListener> {
+    LISTENERS.lock().unwrap().take_unix()
+}
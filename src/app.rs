@@ -2,11 +2,12 @@
 
 use ::actix::dev::*;
 use failure::Error;
-use futures::{future, Future, IntoFuture};
+use futures::{future, Future, IntoFuture, Stream};
 use log::*;
 use serde::{Deserialize, Serialize};
 
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::ops::Deref;
 #[cfg(unix)]
 use std::os::unix::net::{UnixStream, UnixListener};
@@ -15,9 +16,11 @@ use std::path::PathBuf;
 use std::sync::Arc;
 
 use crate::prelude::*;
-use crate::{get_type, router, service};
-use crate::http::HttpFactory;
-use crate::router::Router;
+use crate::{event, get_type, router, service};
+use crate::event::{EventExt, PublishReport, Subscriptions};
+use crate::http::{HttpFactory, ServerHandle};
+use crate::{DirectoryError, ForwardResponse};
+use crate::router::{Groups, IntoMember, Router, RouterError, Scatter};
 
 thread_local!(
     /// Each thread maintains its own `App` struct, which is basically
@@ -25,18 +28,6 @@ thread_local!(
     pub(crate) static APP: Arc<RefCell<App>> = Arc::new(RefCell::new(App::default()))
 );
 
-thread_local!(
-    /// Each thread maintains its own `App` struct, which is basically
-    /// routing information for messages
-    pub(crate) static SOCKET_DIR: tempfile::TempDir = tempfile::tempdir().unwrap();
-);
-
-#[cfg(unix)]
-pub(crate) fn sock_path(name: &str) -> PathBuf {
-    // let tmp_dir = tempdir::TempDir::new("actix-dir").unwrap();
-    SOCKET_DIR.with(|dir| dir.path().join(format!("{}.sock", name)))
-}
-
 /// An application can be seen as a set of independent services, connecting
 /// together through the external routes.
 ///
@@ -56,6 +47,19 @@ pub struct App {
     upstream: Router,
     http: HttpFactory<ServerIn>,
     http_internal: HttpFactory<ClientIn>,
+    raw_internal: HashMap<&'static str, RawHandler>,
+    /// `Subscriptions<E>` for each event type `E`.
+    subscriptions: anymap::AnyMap,
+    /// `Groups<M>` for each message type `M` with route groups.
+    groups: anymap::AnyMap,
+    /// What keeps the plugins added to the app running: their processes,
+    /// or the watches restarting them. Stopped along with the app.
+    #[cfg(unix)]
+    plugins: Vec<Box<dyn std::any::Any>>,
+    #[cfg(unix)]
+    sockets: crate::SocketDir,
+    #[cfg(unix)]
+    peer_policy: Option<crate::http::PeerPolicy>,
     // rpc: crate::rpc::RpcHandler,
 }
 
@@ -76,13 +80,23 @@ impl App {
         http.route::<OpaqueMessage>(None);
         let mut http_internal = HttpFactory::new();
         http_internal.route::<OpaqueMessage>(Some(RouteType::Client));
+        let mut raw_internal: HashMap<&'static str, RawHandler> = HashMap::new();
+        raw_internal.insert(OpaqueMessage::PATH, raw_message::<OpaqueMessage>);
         let client = Router::with_name("client");
         let server = Router::with_name("server");
         let upstream = Router::with_name("upstream");
         // let addr = ClientIn::start_default();
         // let rpc = crate::rpc::RpcHandler::new(addr);
         Self {
-            client, server, upstream, http, http_internal,
+            client, server, upstream, http, http_internal, raw_internal,
+            subscriptions: anymap::AnyMap::new(),
+            groups: anymap::AnyMap::new(),
+            #[cfg(unix)]
+            plugins: Vec::new(),
+            #[cfg(unix)]
+            sockets: crate::SocketDir::default(),
+            #[cfg(unix)]
+            peer_policy: None,
         }
     }
 
@@ -108,16 +122,77 @@ impl App {
     }
 
     /// Set a default fallback route as an upstream route
-    pub fn default_route<R: Into<Remote>>(mut self, remote: R) -> Self {
+    pub fn default_route<R: Into<Upstream>>(mut self, remote: R) -> Self {
         self.upstream.default = Some(remote.into().start());
         self
     }
 
+    /// Deliver the events `E` published on this app to `subscriber`,
+    /// as well as to any earlier subscribers.
+    pub fn subscribe<E, S>(mut self, subscriber: S) -> Self
+        where E: EventExt,
+              S: IntoMember<E>,
+    {
+        let subscriber = subscriber.into_member();
+        log::trace!("Subscribe {} to {:?}", subscriber.name(), get_type!(E));
+        self.subscriptions.entry::<Subscriptions<E>>()
+            .or_insert_with(|| Subscriptions(Vec::new()))
+            .0.push(subscriber);
+        self
+    }
+
+    /// Add `member` to the route group called `group`, which `app::scatter`
+    /// and `app::send_all` send the message `M` to.
+    pub fn group<M, R>(mut self, group: &str, member: R) -> Self
+        where M: MessageExt,
+              R: IntoMember<M>,
+    {
+        let member = member.into_member();
+        log::trace!("Add {} to route group {:?} for {:?}", member.name(), group, get_type!(M));
+        self.groups.entry::<Groups<M>>()
+            .or_insert_with(|| Groups(HashMap::new()))
+            .0.entry(group.to_string()).or_default()
+            .push(member);
+        self
+    }
+
     /// Add a service to the application, usually encapsulates mutliple routes
     pub fn service<S: service::Service>(self, service: S) -> Self {
         service.add_to(self)
     }
 
+    /// Put the application's sockets in `dir`, rather than a temporary directory.
+    #[cfg(unix)]
+    pub fn socket_dir(mut self, dir: crate::SocketDir) -> Self {
+        self.sockets = dir;
+        self
+    }
+
+    /// Only accept requests on `serve_local_http` from processes allowed by `policy`.
+    /// The caller's `PeerCred` is also added to the extensions of each request.
+    #[cfg(unix)]
+    pub fn peer_policy(mut self, policy: crate::http::PeerPolicy) -> Self {
+        self.peer_policy = Some(policy);
+        self
+    }
+
+    /// The path of the socket called `name` in the application's socket directory.
+    #[cfg(unix)]
+    pub fn sock_path(&self, name: &str) -> std::io::Result<PathBuf> {
+        self.sockets.path(name)
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn sockets(&self) -> &crate::SocketDir {
+        &self.sockets
+    }
+
+    /// Keep a plugin running until the app is dropped.
+    #[cfg(unix)]
+    pub(crate) fn keep_plugin<T: 'static>(&mut self, running: T) {
+        self.plugins.push(Box::new(running));
+    }
+
     #[cfg(unix)]
     pub fn plugin(self, plugin: crate::Plugin) -> Self {
         plugin.add_to(self)
@@ -131,6 +206,15 @@ impl App {
         self
     }
 
+    /// Like `expose`, serving the message as configured. Exposing a message
+    /// again replaces its configuration.
+    pub fn expose_with<M>(mut self, config: crate::http::ExposeConfig) -> Self
+        where M: MessageExt
+    {
+        self.http.route_with::<M>(None, config);
+        self
+    }
+
     // pub fn configure(&self, app: actix_web::App<Addr<app::ServerIn>>) -> actix_web::App<Addr<app::ServerIn>> {
     //     self.http.configure(app)
     // }
@@ -156,10 +240,12 @@ impl App {
                 //     self.rpc.route::<M>(path);
                 // }
                 self.http_internal.route::<M>(Some(RouteType::Client));
+                self.raw_internal.insert(M::PATH, raw_message::<M>);
                 self.client.insert(service.into());
             },
             RouteType::Server => {
                 self.http_internal.route::<M>(Some(RouteType::Server));
+                self.raw_internal.insert(M::PATH, raw_message::<M>);
                 self.server.insert(service.into());
             },
             RouteType::Upstream => {
@@ -190,6 +276,18 @@ impl App {
         self
     }
 
+    /// Internal helper method to insert a route for all messages with the given path
+    pub(crate) fn add_path(&mut self, path: &str, service: router::PathRoute, ty: RouteType) -> &mut Self {
+        log::trace!("Add route: /{} on {:?}", path, ty);
+
+        match ty {
+            RouteType::Client => self.client.insert_path(path, service),
+            RouteType::Server => self.server.insert_path(path, service),
+            RouteType::Upstream => self.upstream.insert_path(path, service),
+        };
+        self
+    }
+
     /// Set this application to be the current application default.
     pub fn make_current(self) {
         log::trace!("Setting APP on thread: : {:?}", std::thread::current().id());
@@ -238,6 +336,76 @@ impl App {
         self.upstream.send(msg)
     }
 
+    /// Deliver a message on the default channel without waiting for, or
+    /// expecting, a reply. This only fails if there's no route for it, and
+    /// remotes answer as soon as they have it.
+    pub fn notify<M>(&self, msg: M) -> Result<(), Error>
+        where M: MessageExt
+    {
+        self.notify_local(msg)
+    }
+
+    /// Like `send_local`, without a reply.
+    pub fn notify_local<M>(&self, msg: M) -> Result<(), Error>
+        where M: MessageExt
+    {
+        match self.client.recipient_for(&msg) {
+            Some(r) => do_send(r, msg),
+            None => self.notify_in(msg),
+        }
+    }
+
+    /// Like `send_in`, without a reply.
+    pub fn notify_in<M>(&self, msg: M) -> Result<(), Error>
+        where M: MessageExt
+    {
+        match self.server.recipient_for(&msg) {
+            Some(r) => do_send(r, msg),
+            None => self.notify_out(msg),
+        }
+    }
+
+    /// Like `send_out`, without a reply.
+    pub fn notify_out<M>(&self, msg: M) -> Result<(), Error>
+        where M: MessageExt
+    {
+        match self.upstream.recipient_for(&msg) {
+            Some(r) => do_send(r, msg),
+            None => {
+                error!("No route found for {:?}", get_type!(M));
+                Err(RouterError::default().into())
+            },
+        }
+    }
+
+    /// Deliver `event` to all of its subscribers.
+    pub fn publish<E>(&self, event: E) -> impl Future<Item=PublishReport, Error=Error>
+        where E: EventExt
+    {
+        let subscriptions = self.subscriptions.get::<Subscriptions<E>>().map(|subs| &subs.0[..]).unwrap_or(&[]);
+        event::publish(subscriptions, event)
+    }
+
+    /// Prepare to send `msg` to every member of the route group `group`.
+    pub fn scatter<M>(&self, group: &str, msg: M) -> Scatter<M>
+        where M: MessageExt + Clone
+    {
+        let members = self.groups.get::<Groups<M>>().and_then(|groups| groups.0.get(group)).cloned();
+        Scatter::new(group, members, msg)
+    }
+
+    /// Handle a serialized message addressed to `path`, in the same way as the
+    /// local HTTP server does. Used by transports which don't go through `actix_web`.
+    pub(crate) fn dispatch_raw(&self, path: &str, body: &[u8]) -> Box<dyn Future<Item=Vec<u8>, Error=Error>> {
+        match self.raw_internal.get(path) {
+            Some(handler) => handler(body),
+            None => {
+                error!("No message exposed on path: {:?}", path);
+                Box::new(future::err(router::RouterError::default().into()))
+            },
+        }
+    }
+
     /// Helper function to create a new actix_web application with the routes preconfigured
     pub fn http_server(&self) -> impl Fn() -> actix_web::App<Addr<ServerIn>> + Clone {
         let addr = ServerIn::start_default();
@@ -250,23 +418,87 @@ impl App {
         }
     }
 
+    /// Serve the exposed messages over HTTP on `addr`.
+    pub fn serve_http<A>(&self, addr: A) -> Result<ServerHandle, DirectoryError>
+        where A: std::net::ToSocketAddrs + std::fmt::Debug
+    {
+        let server = actix_web::server::new(self.http_server())
+            .bind(&addr)
+            .map_err(|cause| DirectoryError::Bind { addr: format!("{:?}", addr), cause })?;
+        Ok(crate::http::serve_tcp(server))
+    }
+
+    /// Serve the exposed messages over HTTPS on `addr`, presenting `identity` to clients.
+    #[cfg(feature = "tls")]
+    pub fn serve_https<A>(&self, addr: A, identity: &crate::http::TlsIdentity) -> Result<ServerHandle, DirectoryError>
+        where A: std::net::ToSocketAddrs + std::fmt::Debug
+    {
+        let acceptor = identity.acceptor().map_err(DirectoryError::Tls)?;
+        let server = actix_web::server::new(self.http_server())
+            .bind_tls(&addr, acceptor)
+            .map_err(|cause| DirectoryError::Bind { addr: format!("{:?}", addr), cause })?;
+        Ok(crate::http::serve_tls(server))
+    }
+
+    /// Like `serve_http`, but use the first TCP socket passed in by systemd
+    /// socket activation if there is one, and only bind `addr` otherwise.
     #[cfg(unix)]
-    pub fn serve_local_http(&self, path: Option<std::path::PathBuf>) -> std::path::PathBuf {
+    pub fn serve_http_activated<A>(&self, addr: A) -> Result<ServerHandle, DirectoryError>
+        where A: std::net::ToSocketAddrs + std::fmt::Debug
+    {
+        match crate::activation::take_tcp() {
+            Some(listener) => {
+                info!("Serving HTTP on activated socket {:?}", listener.local_addr());
+                let server = actix_web::server::new(self.http_server()).listen(listener);
+                Ok(crate::http::serve_tcp(server))
+            },
+            None => self.serve_http(addr),
+        }
+    }
+
+    /// Serve the internal routes on the Unix socket at `path`,
+    /// or the socket called "main" in the app's socket directory.
+    #[cfg(unix)]
+    pub fn serve_local_http(&self, path: Option<std::path::PathBuf>) -> Result<ServerHandle, DirectoryError> {
+        let path = match path {
+            Some(path) => path,
+            None => self.sock_path("main")
+                .map_err(|cause| DirectoryError::Bind { addr: "main.sock".to_string(), cause })?,
+        };
+        let listener = self.sockets.bind(&path)
+            .map_err(|cause| DirectoryError::Bind { addr: path.display().to_string(), cause })?;
+        Ok(self.serve_unix(listener, path))
+    }
+
+    /// Like `serve_local_http`, but use the first Unix socket passed in by systemd
+    /// socket activation if there is one, and only bind `path` otherwise.
+    #[cfg(unix)]
+    pub fn serve_local_http_activated(&self, path: Option<std::path::PathBuf>) -> Result<ServerHandle, DirectoryError> {
+        let listener = match crate::activation::take_unix() {
+            Some(listener) => listener,
+            None => return self.serve_local_http(path),
+        };
+        let bound = listener.local_addr().ok().and_then(|addr| addr.as_pathname().map(PathBuf::from));
+        info!("Serving local HTTP on activated socket {:?}", bound);
+        let path = match bound.or(path) {
+            Some(path) => path,
+            None => self.sock_path("main")
+                .map_err(|cause| DirectoryError::Bind { addr: "main.sock".to_string(), cause })?,
+        };
+        let listener = tokio_uds::UnixListener::from_std(listener, &tokio_reactor::Handle::default())
+            .map_err(|cause| DirectoryError::Bind { addr: path.display().to_string(), cause })?;
+        Ok(self.serve_unix(listener, path).keep_socket())
+    }
+
+    #[cfg(unix)]
+    fn serve_unix(&self, listener: tokio_uds::UnixListener, path: PathBuf) -> ServerHandle {
         let addr = ClientIn::start_default();
         let factory = self.http_internal.clone();
-        let path = path.unwrap_or_else(|| sock_path("main"));
-        let listener = tokio_uds::UnixListener::bind(&path).unwrap();
-        // let fd = listener.into_raw_fd();
-        // let tcp_listener = TcpListener::from(fd);
-        actix_web::server::new(move || {
+        crate::http::serve_unix(move || {
             let app = actix_web::App::with_state(addr.clone());
             factory.clone().configure(app)
                 .middleware(actix_web::middleware::Logger::default())
-        })
-        .workers(2)
-        // .bind(&format!("0.0.0.0:{}", port))
-        .start_incoming(listener.incoming(), false);
-        path
+        }, listener, path, self.peer_policy.clone())
     }
 }
 
@@ -278,6 +510,84 @@ pub fn send<M>(msg: M) -> impl Future<Item=M::Response, Error=Error>
         app.borrow().send(msg)
     })}
 
+/// Deliver a message on the default channel of the current app, without a reply.
+pub fn notify<M>(msg: M) -> Result<(), Error>
+    where M: MessageExt
+{
+    APP.with(|app| {
+        app.borrow().notify(msg)
+    })
+}
+
+/// Deliver a message to the local handler, without a reply.
+pub fn notify_local<M>(msg: M) -> Result<(), Error>
+    where M: MessageExt
+{
+    APP.with(|app| {
+        app.borrow().notify_local(msg)
+    })
+}
+
+/// Deliver a message to the handler for incoming messages, without a reply.
+pub fn notify_in<M>(msg: M) -> Result<(), Error>
+    where M: MessageExt
+{
+    APP.with(|app| {
+        app.borrow().notify_in(msg)
+    })
+}
+
+/// Deliver a message to the handler for outgoing messages, without a reply.
+pub fn notify_out<M>(msg: M) -> Result<(), Error>
+    where M: MessageExt
+{
+    APP.with(|app| {
+        app.borrow().notify_out(msg)
+    })
+}
+
+fn do_send<M: MessageExt>(recipient: Recipient<M>, msg: M) -> Result<(), Error> {
+    recipient.do_send(msg).map_err(|err| {
+        error!("Failed to deliver message: {}", err);
+        failure::err_msg(err.to_string())
+    })
+}
+
+/// Publish an event to its subscribers on the current app.
+pub fn publish<E>(event: E) -> impl Future<Item=PublishReport, Error=Error>
+    where E: EventExt
+{
+    APP.with(|app| {
+        app.borrow().publish(event)
+    })
+}
+
+/// Prepare to send `msg` to every member of the route group `group` of the current app.
+pub fn scatter<M>(group: &str, msg: M) -> Scatter<M>
+    where M: MessageExt + Clone
+{
+    APP.with(|app| {
+        app.borrow().scatter(group, msg)
+    })
+}
+
+/// Send `msg` to every member of the route group `group`, and get
+/// all of their replies, or the first failure.
+pub fn send_all<M>(group: &str, msg: M) -> impl Future<Item=Vec<M::Response>, Error=Error>
+    where M: MessageExt + Clone
+{
+    scatter(group, msg).gather().map(|replies| {
+        replies.into_iter().filter_map(|reply| reply.result.ok()).collect()
+    })
+}
+
+/// Send a streaming message on the default channel, and get its items as they arrive.
+pub fn send_stream<M>(msg: M) -> impl Stream<Item=M::Item, Error=Error>
+    where M: crate::StreamingMessageExt
+{
+    send(msg).flatten_stream()
+}
+
 /// Send a message to the local handler
 pub fn send_local<M>(msg: M) -> impl Future<Item=M::Response, Error=Error>
     where M: MessageExt
@@ -305,6 +615,22 @@ pub fn send_out<M>(msg: M) -> impl Future<Item=M::Response, Error=Error>
     })
 }
 
+/// Handler for a message which is still serialized.
+pub(crate) type RawHandler = fn(&[u8]) -> Box<dyn Future<Item=Vec<u8>, Error=Error>>;
+
+/// Deserialize the message as `M`, send it to the local handler, and serialize the response.
+fn raw_message<M>(body: &[u8]) -> Box<dyn Future<Item=Vec<u8>, Error=Error>>
+    where M: MessageExt
+{
+    match crate::deserialize::<M>(body) {
+        Ok(msg) => Box::new(send_local(msg).and_then(|resp| crate::serialize(&resp))),
+        Err(err) => {
+            error!("Failed to deserialize request: {}", err);
+            Box::new(future::err(err))
+        },
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct ServerIn;
 
@@ -315,12 +641,11 @@ impl Actor for ServerIn {
 impl<M> Handler<M> for ServerIn
     where M: MessageExt,
 {
-    type Result = FutResponse<M>;
+    type Result = ForwardResponse<M>;
 
     fn handle(&mut self, msg: M, _ctxt: &mut Context<Self>) -> Self::Result {
         log::trace!("Handling request for server in on thread: {:?}", std::thread::current().id());
-        let res = send_in(msg).map_err(Error::from);
-        FutResponse(Box::new(res))
+        ForwardResponse::new(msg, send_in, |msg| notify_in(msg).into_future())
     }
 }
 
@@ -334,12 +659,11 @@ impl Actor for ClientIn {
 impl<M> Handler<M> for ClientIn
     where M: MessageExt,
 {
-    type Result = FutResponse<M>;
+    type Result = ForwardResponse<M>;
 
     fn handle(&mut self, msg: M, _ctxt: &mut Context<Self>) -> Self::Result {
         log::trace!("Handling request for Clientin on thread: {:?}", std::thread::current().id());
-        let res = send_local(msg).map_err(Error::from);
-        FutResponse(Box::new(res))
+        ForwardResponse::new(msg, send_local, |msg| notify_local(msg).into_future())
     }
 }
 
@@ -386,7 +710,7 @@ pub fn no_server<M: MessageExt>() -> Recipient<M> {
 /// requests and composing the outputs in some way. For example, a `UserInfo` request might
 /// need to correlate data from a database, as well as some other APIs. On the other hand, `UserInfo` might
 /// only need to talk to an upstream server which returns the desired object, but the client might
-/// still want to do some caching or connection pooling.
+/// still want to do some caching (see `CachingClient`) or connection pooling.
 ///
 /// A `Server` is a local handler which responds to incoming messages. For example, the aforementioned
 /// server handling `UserInfo` requests. Although the server may also need to talk to additional resources,
@@ -448,9 +772,43 @@ impl<R, M> Routeable<M> for router::PendingRoute<R>
     }
 }
 
+impl<M> Routeable<M> for router::CachingClient<M>
+    where M: MessageExt,
+          M::Response: Clone,
+{
+    fn route(self, app: &mut App, ty: RouteType)  {
+        app.add_recip(self.start().recipient::<M>(), ty);
+    }
+}
+
+impl<M> Routeable<M> for router::Durable<M>
+    where M: MessageExt,
+{
+    fn route(self, app: &mut App, ty: RouteType)  {
+        app.add_recip(self.start(), ty);
+    }
+}
+
+impl<M> Routeable<M> for router::Limited<M>
+    where M: MessageExt,
+{
+    fn route(self, app: &mut App, ty: RouteType)  {
+        app.add_recip(self.start(), ty);
+    }
+}
+
+impl<M> Routeable<M> for router::SingleFlight<M>
+    where M: MessageExt,
+          M::Response: Clone,
+{
+    fn route(self, app: &mut App, ty: RouteType)  {
+        app.add_recip(self.start().recipient(), ty);
+    }
+}
+
 impl<R, M> Routeable<M> for R
     where M: MessageExt,
-          R: Into<router::Remote> + Clone
+          R: Into<router::Upstream> + Clone
 {
     fn route(self, app: &mut App, ty: RouteType)  {
         Routeable::<M>::route(self.into().start(), app, ty)
@@ -473,7 +831,7 @@ impl<A> Routeable<OpaqueMessage> for (&str, Addr<A>)
 }
 
 impl<R> Routeable<OpaqueMessage> for (&str, R)
-    where R: Into<router::Remote> + Clone
+    where R: Into<router::Upstream> + Clone
 {
     fn route(self, app: &mut App, ty: RouteType)  {
         (self.0, self.1.into().start()).route(app, ty)
@@ -0,0 +1,21 @@
+//! Errors returned when setting up an `App`.
+
+use failure::Fail;
+
+use std::io;
+
+/// Failure to start one of the `App`'s servers.
+#[derive(Debug, Fail)]
+pub enum DirectoryError {
+    /// The server couldn't listen on the requested address.
+    #[fail(display = "failed to bind {}: {}", addr, cause)]
+    Bind {
+        addr: String,
+        #[cause]
+        cause: io::Error,
+    },
+    /// The server's certificate or key couldn't be loaded.
+    #[cfg(feature = "tls")]
+    #[fail(display = "invalid TLS identity: {}", _0)]
+    Tls(#[cause] native_tls::Error),
+}
@@ -0,0 +1,58 @@
+//! Events published to every subscriber, rather than sent to a single handler.
+
+use failure::Error;
+use futures::{future, Future};
+use log::*;
+
+use crate::router::Member;
+use crate::MessageExt;
+
+/// A message broadcast with `app::publish` to the subscribers of its type.
+///
+/// Remote subscribers get it as any other message, so their app
+/// must route and expose it.
+pub trait EventExt: MessageExt<Response=()> + Clone {}
+
+/// The subscribers of an app to the events of type `E`.
+pub(crate) struct Subscriptions<E: EventExt>(pub(crate) Vec<Member<E>>);
+
+/// The outcome of publishing an event to one subscriber.
+#[derive(Debug)]
+pub struct Delivery {
+    /// The subscriber's actor type, or its remote.
+    pub subscriber: String,
+    pub result: Result<(), Error>,
+}
+
+/// The outcome of publishing an event, for each subscriber in the order they subscribed.
+#[derive(Debug, Default)]
+pub struct PublishReport {
+    pub deliveries: Vec<Delivery>,
+}
+
+impl PublishReport {
+    /// Whether every subscriber got the event.
+    pub fn is_ok(&self) -> bool {
+        self.deliveries.iter().all(|delivery| delivery.result.is_ok())
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item=&Delivery> {
+        self.deliveries.iter().filter(|delivery| delivery.result.is_err())
+    }
+}
+
+/// Deliver `event` to each of `subscriptions` at once.
+pub(crate) fn publish<E: EventExt>(subscribers: &[Member<E>], event: E) -> impl Future<Item=PublishReport, Error=Error> {
+    trace!("Publishing {:?} to {} subscribers", event, subscribers.len());
+    let deliveries = subscribers.iter().map(|subscriber| {
+        let delivery = subscriber.send(event.clone());
+        let subscriber = subscriber.name().to_string();
+        delivery.then(move |result| {
+            if let Err(err) = &result {
+                error!("Failed to deliver event to {}: {}", subscriber, err);
+            }
+            Ok::<_, Error>(Delivery { subscriber, result })
+        })
+    }).collect::<Vec<_>>();
+    future::join_all(deliveries).map(|deliveries| PublishReport { deliveries })
+}
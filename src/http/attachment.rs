@@ -0,0 +1,339 @@
+//! Binary attachments, streamed next to a message rather than buffered as part of it.
+//!
+//! An `Attachment` is serialized as its index in the message. A body carrying
+//! attachments is marked with the `X-Attachments` header, and framed as:
+//!
+//! - the encoded message, prefixed by its length as a big-endian `u32`,
+//! - for each attachment in turn, its data in chunks prefixed by their
+//!   length, followed by an empty chunk.
+//!
+//! Attachments are received in order, so a handler must read each one
+//! before the next one arrives. Those it drops are skipped, and the body
+//! isn't read any further once all of them are dropped. Bodies claiming more
+//! than 1024 attachments are refused.
+
+use actix::Arbiter;
+use actix_web::{error::PayloadError, HttpMessage};
+use bytes::{BufMut, Bytes, BytesMut};
+use failure::{err_msg, Error, Fail};
+use futures::{future, stream, sync::mpsc, Async, Future, Poll, Sink, Stream};
+use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use super::{Encoding, PayloadTooLarge};
+
+/// The header giving the number of attachments in a body.
+pub(crate) const HEADER: &str = "x-attachments";
+
+/// Largest chunk of attachment data sent at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Most attachments accepted in a body.
+const MAX_ATTACHMENTS: usize = 1024;
+
+/// A body claims more attachments than are accepted.
+#[derive(Debug, Fail)]
+#[fail(display = "body has {} attachments, but at most {} are accepted", count, max)]
+pub struct TooManyAttachments {
+    pub count: usize,
+    pub max: usize,
+}
+
+type Sender = mpsc::Sender<Result<Bytes, String>>;
+
+type BoxStream = Box<dyn Stream<Item=Bytes, Error=Error> + Send>;
+
+/// The attachments of the message being serialized.
+struct Outgoing {
+    encoding: Encoding,
+    attachments: Vec<BoxStream>,
+}
+
+/// The attachments of the message being deserialized.
+struct Incoming {
+    encoding: Encoding,
+    limit: usize,
+    count: usize,
+    /// The attachments the message refers to, by index.
+    senders: HashMap<usize, Sender>,
+}
+
+thread_local! {
+    #[allow(clippy::missing_const_for_thread_local)]
+    static OUTGOING: RefCell<Option<Outgoing>> = RefCell::new(None);
+    #[allow(clippy::missing_const_for_thread_local)]
+    static INCOMING: RefCell<Option<Incoming>> = RefCell::new(None);
+}
+
+/// The encoding of the message being serialized, if it's going over HTTP.
+pub(crate) fn outgoing_encoding() -> Option<Encoding> {
+    OUTGOING.with(|out| out.borrow().as_ref().map(|out| out.encoding))
+}
+
+/// The encoding and size limit of the message being deserialized, if it came over HTTP.
+pub(crate) fn incoming_encoding() -> Option<(Encoding, usize)> {
+    INCOMING.with(|incoming| incoming.borrow().as_ref().map(|incoming| (incoming.encoding, incoming.limit)))
+}
+
+/// Binary data sent with a message over HTTP without being held in memory.
+///
+/// An attachment can only be sent once, and only to HTTP remotes or local handlers.
+pub struct Attachment(Mutex<Option<BoxStream>>);
+
+impl fmt::Debug for Attachment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Attachment")
+    }
+}
+
+impl Attachment {
+    pub fn new<S>(stream: S) -> Self
+        where S: Stream<Item=Bytes, Error=Error> + Send + 'static
+    {
+        Attachment(Mutex::new(Some(Box::new(stream))))
+    }
+
+    pub fn from_bytes<B: Into<Bytes>>(bytes: B) -> Self {
+        Attachment::new(stream::once(Ok(bytes.into())))
+    }
+
+    /// The data, as it arrives.
+    pub fn into_stream(self) -> impl Stream<Item=Bytes, Error=Error> {
+        match self.0.into_inner().unwrap() {
+            Some(stream) => future::Either::A(stream),
+            None => future::Either::B(stream::once(Err(err_msg("attachment was already sent")))),
+        }
+    }
+
+    /// All of the data, for attachments known to be small enough.
+    pub fn concat(self) -> impl Future<Item=Bytes, Error=Error> {
+        self.into_stream().concat2()
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct Index(u32);
+
+impl Serialize for Attachment {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Only taken once it can be sent, so it's still there when it can't
+        let index = OUTGOING.with(|out| {
+            let mut out = out.borrow_mut();
+            let out = out.as_mut().ok_or_else(|| ser::Error::custom("attachments can only be sent over HTTP"))?;
+            let stream = self.0.lock().unwrap().take()
+                .ok_or_else(|| ser::Error::custom("attachment was already sent"))?;
+            out.attachments.push(stream);
+            Ok(out.attachments.len() - 1)
+        })?;
+        Index(index as u32).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Attachment {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let Index(index) = Index::deserialize(deserializer)?;
+        let index = index as usize;
+        // The channel is only made once the message refers to the attachment
+        let rx = INCOMING.with(|incoming| {
+            let mut incoming = incoming.borrow_mut();
+            let incoming = incoming.as_mut().filter(|incoming| index < incoming.count)?;
+            if incoming.senders.contains_key(&index) {
+                return None;
+            }
+            let (tx, rx) = mpsc::channel::<Result<Bytes, String>>(1);
+            incoming.senders.insert(index, tx);
+            Some(rx)
+        }).ok_or_else(|| de::Error::custom(format!("no attachment {} in this body", index)))?;
+        let stream: BoxStream = Box::new(rx.then(|item| match item {
+            Ok(Ok(data)) => Ok(data),
+            Ok(Err(err)) => Err(err_msg(err)),
+            Err(()) => Err(err_msg("attachment was interrupted")),
+        }));
+        Ok(Attachment(Mutex::new(Some(stream))))
+    }
+}
+
+/// A serialized message, with its attachments if it has any.
+pub(crate) enum Body {
+    Full(Vec<u8>),
+    Framed {
+        attachments: usize,
+        stream: Box<dyn Stream<Item=Bytes, Error=Error>>,
+    },
+}
+
+/// Serialize `msg`, collecting the attachments it contains.
+pub(crate) fn serialize<M: Serialize>(encoding: Encoding, msg: &M) -> Result<Body, Error> {
+    let outer = OUTGOING.with(|out| out.replace(Some(Outgoing { encoding, attachments: Vec::new() })));
+    let res = encoding.serialize(msg);
+    let attachments = OUTGOING.with(|out| out.replace(outer)).map(|out| out.attachments).unwrap_or_default();
+    let msg = res?;
+    if attachments.is_empty() {
+        return Ok(Body::Full(msg));
+    }
+    let head = frame(&msg);
+    let count = attachments.len();
+    let data = stream::iter_ok::<_, Error>(attachments)
+        .map(|attachment| {
+            attachment.map(|data| stream::iter_ok(chunks(data))).flatten()
+                      .chain(stream::once(Ok(Bytes::from_static(&[0, 0, 0, 0]))))
+        })
+        .flatten();
+    Ok(Body::Framed { attachments: count, stream: Box::new(stream::once(Ok(head)).chain(data)) })
+}
+
+/// Split `data` into length-prefixed chunks.
+fn chunks(data: Bytes) -> Vec<Bytes> {
+    data.chunks(CHUNK_SIZE).map(frame).collect()
+}
+
+/// `data`, prefixed by its length.
+pub(crate) fn frame(data: &[u8]) -> Bytes {
+    let mut framed = BytesMut::with_capacity(4 + data.len());
+    framed.put_u32_be(data.len() as u32);
+    framed.put_slice(data);
+    framed.freeze()
+}
+
+/// The length of the frame at the start of `buf`, once its prefix has arrived.
+pub(crate) fn frame_len(buf: &BytesMut) -> Option<usize> {
+    if buf.len() < 4 {
+        return None;
+    }
+    Some(u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize)
+}
+
+/// The number of attachments of a body, if it's framed.
+pub(crate) fn count<H: HttpMessage>(msg: &H) -> Option<usize> {
+    msg.headers().get(HEADER).and_then(|count| count.to_str().ok()).and_then(|count| count.parse().ok())
+}
+
+enum Frame {
+    Message(Bytes),
+    Chunk(Bytes),
+    End,
+}
+
+/// Splits a framed body back into its parts.
+struct Frames<S> {
+    payload: S,
+    buf: BytesMut,
+    /// The largest message accepted.
+    limit: usize,
+    started: bool,
+}
+
+impl<S> Frames<S> {
+    fn parse(&mut self) -> Result<Option<Frame>, Error> {
+        let len = match frame_len(&self.buf) {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        if !self.started && len > self.limit {
+            return Err(PayloadTooLarge { limit: self.limit }.into());
+        }
+        if self.started && len > CHUNK_SIZE {
+            return Err(err_msg("attachment chunk is too large"));
+        }
+        if self.buf.len() < 4 + len {
+            return Ok(None);
+        }
+        self.buf.advance(4);
+        let data = self.buf.split_to(len).freeze();
+        Ok(Some(match (self.started, len) {
+            (false, _) => {
+                self.started = true;
+                Frame::Message(data)
+            },
+            (true, 0) => Frame::End,
+            (true, _) => Frame::Chunk(data),
+        }))
+    }
+}
+
+impl<S: Stream<Item=Bytes, Error=PayloadError>> Stream for Frames<S> {
+    type Item = Frame;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Frame>, Error> {
+        loop {
+            if let Some(frame) = self.parse()? {
+                return Ok(Async::Ready(Some(frame)));
+            }
+            match self.payload.poll().map_err(Error::from)? {
+                Async::Ready(Some(data)) => self.buf.extend_from_slice(&data),
+                Async::Ready(None) if self.buf.is_empty() => return Ok(Async::Ready(None)),
+                Async::Ready(None) => return Err(err_msg("framed body is truncated")),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+/// Where the data of the next attachment goes.
+enum Target {
+    /// The attachment hasn't started yet.
+    Next,
+    Open(Sender),
+    /// The attachment was dropped, skip its data.
+    Skip,
+}
+
+/// Deserialize the message of a framed body, and stream its `count`
+/// attachments from the rest of the `payload`.
+pub(crate) fn deserialize<M, S>(encoding: Encoding, payload: S, count: usize, limit: usize) -> impl Future<Item=M, Error=Error>
+    where
+        M: serde::de::DeserializeOwned,
+        S: Stream<Item=Bytes, Error=PayloadError> + 'static,
+{
+    // Each attachment is referred to by at least a byte of the message
+    let max = MAX_ATTACHMENTS.min(limit);
+    if count > max {
+        return future::Either::A(future::err(TooManyAttachments { count, max }.into()));
+    }
+    let frames = Frames { payload, buf: BytesMut::new(), limit, started: false };
+    future::Either::B(frames.into_future().map_err(|(err, _)| err).and_then(move |(first, frames)| {
+        let msg = match first {
+            Some(Frame::Message(msg)) => msg,
+            _ => return Err(err_msg("framed body has no message")),
+        };
+        let outer = INCOMING.with(|incoming| incoming.replace(Some(Incoming { encoding, limit, count, senders: HashMap::new() })));
+        let res = encoding.deserialize(&msg);
+        let senders = INCOMING.with(|incoming| incoming.replace(outer)).map(|incoming| incoming.senders).unwrap_or_default();
+        Arbiter::spawn(forward(frames, senders));
+        res
+    }))
+}
+
+/// Hand the attachment data in `frames` to their receivers, by index.
+fn forward<S>(frames: Frames<S>, senders: HashMap<usize, Sender>) -> impl Future<Item=(), Error=()>
+    where S: Stream<Item=Bytes, Error=PayloadError>
+{
+    frames.then(Ok::<_, ()>).fold((senders, 0, Target::Next), |(mut senders, next, target), frame| {
+        let (next, target) = match target {
+            Target::Next => (next + 1, senders.remove(&next).map(Target::Open).unwrap_or(Target::Skip)),
+            target => (next, target),
+        };
+        match (frame, target) {
+            (Ok(Frame::Chunk(data)), Target::Open(tx)) => future::Either::A(tx.send(Ok(data)).then(move |res| match res {
+                Ok(tx) => Ok((senders, next, Target::Open(tx))),
+                // Nobody wants the rest of the body, stop reading it
+                Err(_) if senders.values().all(Sender::is_closed) => Err(()),
+                Err(_) => Ok((senders, next, Target::Skip)),
+            })),
+            (Ok(Frame::Chunk(_)), target) => future::Either::B(future::ok((senders, next, target))),
+            (Ok(Frame::End), _) => future::Either::B(future::ok((senders, next, Target::Next))),
+            (Ok(Frame::Message(_)), target) | (Err(_), target) => {
+                // Make sure the receiver doesn't mistake a broken body for the end of its data
+                if let Target::Open(mut tx) = target {
+                    let _ = tx.try_send(Err("framed body is broken".to_string()));
+                }
+                future::Either::B(future::err(()))
+            },
+        }
+    }).map(|_| ())
+}
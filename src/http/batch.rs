@@ -0,0 +1,282 @@
+//! Many messages sent to the same remote in a single request.
+//!
+//! A batch is posted to `/_batch` as a list of (path, body) entries, each body
+//! encoded like the request itself. The reply has the outcome of each entry,
+//! in order, so one failing message doesn't fail the others.
+//!
+//! Servers only take batches while they expose messages, and not at all if
+//! one of those is exposed on `/_batch` itself.
+
+use actix::{Actor, Addr, Arbiter, Context, Handler, Message};
+use actix_web::{AsyncResponder, HttpRequest, HttpResponse};
+use failure::{err_msg, Error, Fail};
+use futures::{future, sync::oneshot, Future};
+use log::*;
+use serde::{Deserialize, Serialize};
+use tokio::timer::Delay;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::MessageExt;
+use crate::limit::{self, Limiter};
+use super::{Encoding, PayloadTooLarge, RemoteError};
+
+/// Most entries a server accepts in a batch, which may all be of its largest
+/// exposed message.
+const MAX_ENTRIES: usize = 64;
+
+/// Room for the path and framing of each entry of a batch.
+const ENTRY_OVERHEAD: usize = 256;
+
+/// How an upstream coalesces messages into batches.
+///
+/// Messages with attachments, which can't be batched, are sent on their own.
+/// Streaming messages can't be sent to a batching upstream.
+#[derive(Clone, Copy, Debug)]
+pub struct BatchConfig {
+    /// How long the first message of a batch waits for others.
+    pub window: Duration,
+    /// Batches are sent as soon as they have this many messages. Servers
+    /// refuse batches of more than 64.
+    pub max_size: usize,
+}
+
+/// A batch has more entries than the server accepts.
+#[derive(Debug, Fail)]
+#[fail(display = "batch has {} entries, but at most {} are accepted", count, max)]
+pub struct TooManyEntries {
+    pub count: usize,
+    pub max: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            window: Duration::from_millis(5),
+            max_size: 64,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Entry {
+    path: String,
+    #[serde(with = "serde_bytes")]
+    body: Vec<u8>,
+}
+
+/// The outcome of one entry of a batch.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Outcome {
+    Ok(#[serde(with = "serde_bytes")] Vec<u8>),
+    Err { status: u16, error: String },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Batch(Vec<Entry>);
+
+impl Batch {
+    pub(crate) fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl Message for Batch {
+    type Result = Vec<Outcome>;
+}
+
+impl MessageExt for Batch {
+    const PATH: &'static str = "_batch";
+
+    type Response = Vec<Outcome>;
+}
+
+type Reply = oneshot::Sender<Result<Vec<u8>, Error>>;
+
+#[derive(Default)]
+struct Pending {
+    /// Bumped whenever a batch is sent, so the timers of earlier batches don't send this one.
+    generation: u64,
+    entries: Vec<Entry>,
+    replies: Vec<Reply>,
+}
+
+impl Pending {
+    fn take(&mut self) -> (Batch, Vec<Reply>) {
+        self.generation += 1;
+        (Batch(self.entries.split_off(0)), self.replies.split_off(0))
+    }
+}
+
+/// The messages waiting to be sent in the next batch to an upstream.
+/// Shared between clones.
+#[derive(Clone, Default)]
+pub(crate) struct Batcher(Arc<Mutex<Pending>>);
+
+impl std::fmt::Debug for Batcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_tuple("Batcher").field(&self.0.lock().unwrap().entries.len()).finish()
+    }
+}
+
+impl Batcher {
+    /// Add the encoded `body` of an `M` to the next batch, which `flush` sends
+    /// once it's full or `config.window` has passed.
+    pub(crate) fn send<M, F>(&self, body: Vec<u8>, encoding: Encoding, config: BatchConfig, flush: F) -> impl Future<Item=M::Response, Error=Error>
+        where M: MessageExt,
+              F: 'static + FnOnce(Batch) -> Box<dyn Future<Item=Vec<Outcome>, Error=Error>>,
+    {
+        let (tx, rx) = oneshot::channel();
+        let batcher = self.clone();
+        future::lazy(move || {
+            let mut pending = batcher.0.lock().unwrap();
+            pending.entries.push(Entry { path: M::PATH.to_string(), body });
+            pending.replies.push(tx);
+            if pending.entries.len() >= config.max_size {
+                let (batch, replies) = pending.take();
+                Arbiter::spawn(deliver(batch, replies, flush));
+            } else if pending.entries.len() == 1 {
+                let (generation, batcher) = (pending.generation, batcher.clone());
+                Arbiter::spawn(Delay::new(Instant::now() + config.window).then(move |_| {
+                    let mut pending = batcher.0.lock().unwrap();
+                    if pending.generation != generation {
+                        return future::Either::A(future::ok(()));
+                    }
+                    let (batch, replies) = pending.take();
+                    future::Either::B(deliver(batch, replies, flush))
+                }));
+            }
+            Ok(())
+        })
+        .and_then(|()| rx.map_err(|_| err_msg("batch was dropped")))
+        .and_then(|res| res)
+        .and_then(move |body| encoding.deserialize(&body))
+    }
+}
+
+/// Send `batch`, and hand each outcome to the message it's for.
+fn deliver<F>(batch: Batch, replies: Vec<Reply>, flush: F) -> impl Future<Item=(), Error=()>
+    where F: FnOnce(Batch) -> Box<dyn Future<Item=Vec<Outcome>, Error=Error>>,
+{
+    trace!("Sending a batch of {} messages", batch.len());
+    flush(batch).then(move |res| {
+        match res {
+            Ok(ref outcomes) if outcomes.len() != replies.len() => {
+                error!("Got {} outcomes for a batch of {} messages", outcomes.len(), replies.len());
+                for reply in replies {
+                    let _ = reply.send(Err(err_msg("batch reply is missing outcomes")));
+                }
+            },
+            Ok(outcomes) => {
+                for (reply, outcome) in replies.into_iter().zip(outcomes) {
+                    let _ = reply.send(match outcome {
+                        Outcome::Ok(body) => Ok(body),
+                        Outcome::Err { status, error } => Err(RemoteError { status, message: error }.into()),
+                    });
+                }
+            },
+            Err(err) => {
+                // Every message failed the same way, keeping the status if it was the server's doing
+                let remote = err.downcast_ref::<RemoteError>().map(|err| (err.status, err.message.clone()));
+                let message = err.to_string();
+                for reply in replies {
+                    let _ = reply.send(Err(match remote {
+                        Some((status, ref message)) => RemoteError { status, message: message.clone() }.into(),
+                        None => err_msg(message.clone()),
+                    }));
+                }
+            },
+        }
+        Ok(())
+    })
+}
+
+/// Decodes the body of an entry of the batch `req`, and encodes the reply to it.
+pub(crate) type EntryHandler<A> = fn(&HttpRequest<Addr<A>>, Encoding, &[u8]) -> Box<dyn Future<Item=Vec<u8>, Error=Error>>;
+
+/// How a message is served in batches.
+pub(crate) struct BatchRoute<A: Actor> {
+    pub(crate) handler: EntryHandler<A>,
+    /// Largest body of the message accepted.
+    pub(crate) limit: usize,
+    /// Limits each entry for the message, like its requests.
+    pub(crate) limiter: Option<Limiter>,
+}
+
+impl<A: Actor> Clone for BatchRoute<A> {
+    fn clone(&self) -> Self {
+        BatchRoute { handler: self.handler, limit: self.limit, limiter: self.limiter.clone() }
+    }
+}
+
+/// The messages served in batches, by path.
+pub(crate) type Handlers<A> = HashMap<&'static str, BatchRoute<A>>;
+
+/// Largest batch accepted for `handlers`, in bytes, after decompression.
+pub(crate) fn limit<A: Actor>(handlers: &Handlers<A>) -> usize {
+    let largest = handlers.values().map(|route| route.limit).max().unwrap_or(0);
+    largest.saturating_add(ENTRY_OVERHEAD).saturating_mul(MAX_ENTRIES)
+}
+
+pub(crate) fn handle_entry<M, A>(req: &HttpRequest<Addr<A>>, encoding: Encoding, body: &[u8]) -> Box<dyn Future<Item=Vec<u8>, Error=Error>>
+    where M: MessageExt,
+          A: Actor<Context=Context<A>> + Handler<M>,
+{
+    match encoding.deserialize::<M>(body) {
+        Ok(mut msg) => {
+            super::server::set_peer(req, &mut msg);
+            Box::new(req.state().send(msg).map_err(Error::from).and_then(move |resp| encoding.serialize(&resp)))
+        },
+        Err(err) => {
+            error!("Failed to deserialize batched request: {}", err);
+            Box::new(future::err(err))
+        },
+    }
+}
+
+/// Serve a batch of at most `limit` bytes and `MAX_ENTRIES` entries with `handlers`,
+/// handling its entries at the same time.
+pub(crate) fn handle_batch<A>(req: HttpRequest<Addr<A>>, handlers: &Arc<Handlers<A>>, limit: usize) -> impl actix_web::Responder
+    where A: Actor<Context=Context<A>>,
+{
+    let (head, handlers) = (req.clone(), handlers.clone());
+    let encoding = Encoding::of(&req);
+    super::read_body(req, limit)
+        .and_then(move |body| encoding.deserialize::<Batch>(&body))
+        .and_then(|Batch(entries)| match entries.len() {
+            count if count > MAX_ENTRIES => Err(TooManyEntries { count, max: MAX_ENTRIES }.into()),
+            _ => Ok(entries),
+        })
+        .and_then(move |entries| {
+            trace!("Handling a batch of {} messages", entries.len());
+            future::join_all(entries.into_iter().map(move |entry| {
+                let fut = match handlers.get(entry.path.as_str()) {
+                    Some(route) if entry.body.len() > route.limit => Box::new(future::err(PayloadTooLarge { limit: route.limit }.into())),
+                    Some(route) => {
+                        let (handler, req, body) = (route.handler, head.clone(), entry.body);
+                        limit::limited(route.limiter.as_ref(), future::lazy(move || handler(&req, encoding, &body)))
+                    },
+                    None => {
+                        error!("No message exposed on path: {:?}", entry.path);
+                        let error = format!("no message exposed on path {:?}", entry.path);
+                        return future::Either::A(future::ok(Outcome::Err { status: 404, error }));
+                    },
+                };
+                future::Either::B(fut.then(|res| Ok::<_, Error>(match res {
+                    Ok(body) => Outcome::Ok(body),
+                    Err(err) => Outcome::Err { status: super::status_for(&err).as_u16(), error: err.to_string() },
+                })))
+            }).collect::<Vec<_>>())
+        })
+        .and_then(move |outcomes| encoding.serialize(&outcomes))
+        .then(move |res| -> Result<HttpResponse, Error> {
+            Ok(match res {
+                Ok(body) => HttpResponse::Ok().content_type(encoding.content_type()).body(body),
+                Err(err) => super::error_response(encoding, &err),
+            })
+        })
+        .responder()
+}
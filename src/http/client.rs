@@ -1,77 +1,285 @@
-use actix_web::{client::ClientRequest, HttpMessage};
+#[cfg(feature = "tls")]
+use actix::Actor;
+use actix::Addr;
+#[cfg(unix)]
+use actix_web::client::Connection;
+use actix_web::{client::{ClientConnector, ClientRequest, ClientRequestBuilder, ClientResponse}, http::{header, ContentEncoding}, HttpMessage};
 use failure::Error;
 use futures::{future, Future};
 use log::*;
 use url::Url;
 
 #[cfg(unix)]
-use std::os::unix::net::{UnixStream, UnixListener};
-#[cfg(unix)]
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use std::time::Duration;
 
-use crate::{deserialize, serialize};
 use crate::MessageExt;
+use super::{attachment::{self, Body}, Coding, Compression, Encoding, Pool, PoolConfig, RemoteError};
+
+/// Options for talking to a single upstream server.
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// How request and response bodies are encoded.
+    pub encoding: Encoding,
+    /// Certificates to trust for `https://` URLs, if not the system's.
+    #[cfg(feature = "tls")]
+    pub tls: Option<super::TlsConfig>,
+    /// How connections are reused between requests.
+    pub pool: PoolConfig,
+    /// Which request bodies are compressed. Responses are always
+    /// decompressed, so this only needs the remote to understand the coding.
+    pub compression: Compression,
+    /// Largest response accepted, in bytes, after decompression.
+    /// Attachments don't count towards it, since they aren't buffered.
+    pub limit: usize,
+    /// Whether messages to the same upstream are sent in batches.
+    pub batch: Option<super::BatchConfig>,
+    /// Limits for the requests to the upstream, shared with every
+    /// upstream configured with a clone of it.
+    pub limiter: Option<crate::Limiter>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            encoding: Encoding::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+            pool: PoolConfig::default(),
+            compression: Compression::default(),
+            limit: super::DEFAULT_LIMIT,
+            batch: None,
+            limiter: None,
+        }
+    }
+}
+
+/// Serialize and compress `msg` for `config`.
+/// Bodies with attachments are streamed as they are.
+fn encode<M: MessageExt>(msg: &M, config: &ClientConfig) -> Result<(Body, Option<Coding>), Error> {
+    match attachment::serialize(config.encoding, msg)? {
+        Body::Full(body) => match config.compression.for_request(&body) {
+            Some(coding) => Ok((Body::Full(coding.compress(&body)?), Some(coding))),
+            None => Ok((Body::Full(body), None)),
+        },
+        framed => Ok((framed, None)),
+    }
+}
+
+/// Finish a request carrying `body`, leaving its compression to us.
+/// Without `reply`, the remote is asked to accept the message without handling it first.
+/// With a `key`, the remote can tell the message apart from others sent again.
+fn post(mut req: ClientRequestBuilder, (body, coding): (Body, Option<Coding>), encoding: Encoding, reply: bool, key: Option<&str>) -> Result<ClientRequest, Error> {
+    if let Some(coding) = coding {
+        req.header(header::CONTENT_ENCODING, coding.as_str());
+    }
+    if !reply {
+        req.header(super::PREFER, super::RESPOND_ASYNC);
+    }
+    if let Some(key) = key {
+        req.header(super::IDEMPOTENCY_KEY, key);
+    }
+    req.content_type(encoding.content_type())
+        .header(header::ACCEPT_ENCODING, super::compression::ACCEPT_ENCODING)
+        .content_encoding(ContentEncoding::Identity)
+        .disable_decompress()
+        .timeout(Duration::from_secs(60 * 60));
+    match body {
+        Body::Full(body) => req.body(body),
+        Body::Framed { attachments, stream } => {
+            req.header(attachment::HEADER, attachments.to_string()).streaming(stream)
+        },
+    }.map_err(|err| failure::err_msg(err.to_string()))
+}
 
 pub fn send<M>(msg: &M, url: Url) -> impl Future<Item=M::Response, Error=Error>
     where M: MessageExt,
+{
+    send_with(msg, url, &ClientConfig::default())
+}
+
+/// Like `send`, with non-default client options.
+pub fn send_with<M>(msg: &M, url: Url, config: &ClientConfig) -> impl Future<Item=M::Response, Error=Error>
+    where M: MessageExt,
+{
+    // One-off requests only need their own connector for custom TLS settings
+    #[cfg(feature = "tls")]
+    let connector = match config.tls {
+        Some(_) => super::pool::new_connector(config).map(|connector| Some(connector.start())),
+        None => Ok(None),
+    };
+    #[cfg(not(feature = "tls"))]
+    let connector = Ok(None);
+    request(msg, url, config, connector, true, None, read_response::<M>)
+}
+
+/// Like `send`, reusing the connections of `pool`, and naming the message with `key` if given.
+pub(crate) fn send_pooled<M>(msg: &M, url: Url, config: &ClientConfig, pool: &Pool, key: Option<String>) -> impl Future<Item=M::Response, Error=Error>
+    where M: MessageExt,
+{
+    request(msg, url, config, pool.connector(config).map(Some), true, key, read_response::<M>)
+}
+
+/// Like `send_pooled`, without waiting for the message to be handled:
+/// this only fails if the remote doesn't accept it.
+pub(crate) fn notify_pooled<M>(msg: &M, url: Url, config: &ClientConfig, pool: &Pool) -> impl Future<Item=(), Error=Error>
+    where M: MessageExt,
+{
+    request(msg, url, config, pool.connector(config).map(Some), false, None, read_ack)
+}
+
+fn request<M, T, F, R>(msg: &M, url: Url, config: &ClientConfig, connector: Result<Option<Addr<ClientConnector>>, Error>, reply: bool, key: Option<String>, read: F) -> impl Future<Item=T, Error=Error>
+    where M: MessageExt,
+          F: 'static + FnOnce(ClientResponse, Encoding, usize) -> R,
+          R: Future<Item=T, Error=Error>,
 {
     // let path = url.path().to_string();
-    let msg = serialize(&msg).map_err(Error::from);
+    let (encoding, limit) = (config.encoding, config.limit);
+    let msg = encode(msg, config);
     trace!("Channel making request to Actor running at {:?} on path {}", url, M::PATH);
-    future::result(msg).and_then(move |msg| {
-        ClientRequest::post(url.join(M::PATH).unwrap())
-            .timeout(Duration::from_secs(60 * 60))
-            .body(msg)
-            .unwrap()
-            .send()
+    future::result(msg.and_then(|msg| connector.map(|connector| (msg, connector)))).and_then(move |(msg, connector)| {
+        let mut req = ClientRequest::post(url.join(M::PATH).unwrap());
+        if let Some(connector) = connector {
+            req.with_connector(connector);
+        }
+        future::result(post(req, msg, encoding, reply, key.as_deref())).and_then(|req| req.send()
             .map_err(|e| {
                 error!("Failed to send HTTP request: {:?} ", e);
                 Error::from(e)
-            })
-            .and_then(|resp| {
-                resp.body().map_err(|e| {
-                    error!("Could not get bytes: {:?} ", e);
-                    Error::from(e)
-                })
-            })
-            .and_then(|body| {
-                future::result(deserialize(&body))
-                    .map_err(|e| {
-                        error!("Failed to deserialize body: {:?} ", e);
-                        Error::from(e)
-                    })
-            })
+            }))
+            .and_then(move |resp| read(resp, encoding, limit))
     })
 }
 
 #[cfg(unix)]
 pub fn send_local<M>(msg: &M, path: &Path) -> impl Future<Item=M::Response, Error=Error>
     where M: MessageExt,
+{
+    send_local_with(msg, path, &ClientConfig::default())
+}
+
+/// Like `send_local`, with non-default client options.
+#[cfg(unix)]
+pub fn send_local_with<M>(msg: &M, path: &Path, config: &ClientConfig) -> impl Future<Item=M::Response, Error=Error>
+    where M: MessageExt,
 {
     // let path = url.path().to_string();
     trace!("Sending message: {:?} to {:?}", msg, path);
-    let msg = serialize(&msg).map_err(Error::from);
-    trace!("Serialized: {:?}", msg);
+    let (encoding, limit) = (config.encoding, config.limit);
+    let msg = encode(msg, config);
     trace!("Channel making request to Actor running on local socket at {:?}", path);
-    tokio_uds::UnixStream::connect(path).from_err().and_then(|uds| {
+    crate::socket::connect(path).from_err().and_then(|uds| {
         future::result(msg.map(|msg| (msg, uds)))
     })
-    .and_then(|(msg, uds)| {
-        let conn = actix_web::client::Connection::from_stream(uds);
-        ClientRequest::post(format!("/{}", M::PATH))
-            .timeout(Duration::from_secs(60 * 60))
-            .with_connection(conn)
-            .body(msg)
-            .unwrap()
-            .send()
-            .map_err(Error::from)
-            .and_then(|resp| {
-                resp.body().map_err(Error::from)
-            })
-            .and_then(|body| {
-                future::result(deserialize(&body))
-                    .map_err(Error::from)
+    .and_then(move |(msg, uds)| {
+        request_local::<M, _, _, _>(msg, Connection::from_stream(uds), encoding, limit, true, None, read_response::<M>).map(|(resp, _)| resp)
+    })
+}
+
+/// Like `send_local`, reusing the connections of `pool`, and naming the message with `key` if given.
+#[cfg(unix)]
+pub(crate) fn send_local_pooled<M>(msg: &M, path: &Path, config: &ClientConfig, pool: &Pool, key: Option<String>) -> impl Future<Item=M::Response, Error=Error>
+    where M: MessageExt,
+{
+    request_local_pooled(msg, path, config, pool, true, key, read_response::<M>)
+}
+
+/// Like `notify_pooled`, for a server on a local socket.
+#[cfg(unix)]
+pub(crate) fn notify_local_pooled<M>(msg: &M, path: &Path, config: &ClientConfig, pool: &Pool) -> impl Future<Item=(), Error=Error>
+    where M: MessageExt,
+{
+    request_local_pooled(msg, path, config, pool, false, None, read_ack)
+}
+
+#[cfg(unix)]
+fn request_local_pooled<M, T, F, R>(msg: &M, path: &Path, config: &ClientConfig, pool: &Pool, reply: bool, key: Option<String>, read: F) -> impl Future<Item=T, Error=Error>
+    where M: MessageExt,
+          F: 'static + FnOnce(ClientResponse, Encoding, usize) -> R,
+          R: Future<Item=T, Error=Error>,
+{
+    trace!("Sending message: {:?} to {:?}", msg, path);
+    let (encoding, limit) = (config.encoding, config.limit);
+    let msg = encode(msg, config);
+    let conn = pool.get(path, config.pool);
+    future::result(msg).and_then(move |msg| conn.map(|conn| (msg, conn)))
+        .and_then(move |(msg, (conn, lease))| {
+            request_local::<M, _, _, _>(msg, conn, encoding, limit, reply, key, read).then(move |res| {
+                // Attachments may be dropped before the end of the body, which
+                // would be left behind on the connection
+                lease.release(res.as_ref().map(|(_, framed)| !framed).unwrap_or(false));
+                res.map(|(resp, _)| resp)
             })
+        })
+}
+
+/// The response, and whether it had attachments.
+#[cfg(unix)]
+fn request_local<M, T, F, R>(msg: (Body, Option<Coding>), conn: Connection, encoding: Encoding, limit: usize, reply: bool, key: Option<String>, read: F) -> impl Future<Item=(T, bool), Error=Error>
+    where M: MessageExt,
+          F: 'static + FnOnce(ClientResponse, Encoding, usize) -> R,
+          R: Future<Item=T, Error=Error>,
+{
+    let mut req = ClientRequest::post(format!("/{}", M::PATH));
+    req.with_connection(conn);
+    future::result(post(req, msg, encoding, reply, key.as_deref()))
+        .and_then(|req| req.send().map_err(Error::from))
+        .and_then(move |resp| {
+            let framed = attachment::count(&resp).is_some();
+            read(resp, encoding, limit).map(move |resp| (resp, framed))
+        })
+}
+
+/// Decode the response body, or the error envelope of a failed request.
+/// At most `limit` bytes of it are read, not counting attachments.
+fn read_response<M>(resp: ClientResponse, encoding: Encoding, limit: usize) -> impl Future<Item=M::Response, Error=Error>
+    where M: MessageExt,
+{
+    let status = resp.status();
+    if let (true, Some(count)) = (status.is_success(), attachment::count(&resp)) {
+        return future::Either::A(attachment::deserialize(encoding, resp.payload(), count, limit));
+    }
+    future::Either::B(read_body(resp, limit)
+        .and_then(move |body| {
+            if status.is_success() {
+                encoding.deserialize(&body).map_err(|e| {
+                    error!("Failed to deserialize body: {:?} ", e);
+                    e
+                })
+            } else {
+                Err(remote_error(status.as_u16(), encoding, &body))
+            }
+        }))
+}
+
+/// Check that the remote accepted a message sent without expecting a reply.
+fn read_ack(resp: ClientResponse, encoding: Encoding, limit: usize) -> impl Future<Item=(), Error=Error> {
+    let status = resp.status();
+    read_body(resp, limit).and_then(move |body| {
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(remote_error(status.as_u16(), encoding, &body))
+        }
     })
 }
+
+/// The decompressed body of `resp`, up to `limit` bytes.
+fn read_body(resp: ClientResponse, limit: usize) -> impl Future<Item=Vec<u8>, Error=Error> {
+    let coding = Coding::of(&resp);
+    resp.body().limit(limit)
+        .map_err(move |e| {
+            error!("Could not get bytes: {:?} ", e);
+            super::payload_error(e, limit)
+        })
+        .and_then(move |body| match coding? {
+            Some(coding) => coding.decompress_limited(&body, limit),
+            None => Ok(body.to_vec()),
+        })
+}
+
+fn remote_error(status: u16, encoding: Encoding, body: &[u8]) -> Error {
+    let err = RemoteError::from_body(status, encoding, body);
+    error!("Request failed: {}", err);
+    Error::from(err)
+}
@@ -0,0 +1,143 @@
+//! `Content-Encoding` of request and response bodies.
+//!
+//! Clients always accept every supported coding, and compress requests as
+//! configured in their `ClientConfig`. Servers compress responses as
+//! configured for each exposed message, if the client accepts it.
+//!
+//! zstd is only supported with the `zstd` feature, which needs a newer
+//! compiler than the rest of the crate.
+
+use actix_web::{http::header, HttpMessage};
+use flate2::{read::{GzDecoder, ZlibDecoder}, write::{GzEncoder, ZlibEncoder}};
+use failure::Error;
+use serde::{Deserialize, Serialize};
+
+use std::io::{self, Read, Write};
+
+use super::PayloadTooLarge;
+
+/// Compression algorithms for bodies, named as in `Content-Encoding`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Coding {
+    #[cfg(feature = "zstd")]
+    Zstd,
+    Gzip,
+    /// zlib, as `deflate` means in HTTP.
+    Deflate,
+}
+
+/// The value of `Accept-Encoding` sent by clients.
+#[cfg(feature = "zstd")]
+pub(crate) const ACCEPT_ENCODING: &str = "zstd, gzip, deflate";
+#[cfg(not(feature = "zstd"))]
+pub(crate) const ACCEPT_ENCODING: &str = "gzip, deflate";
+
+impl Coding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            #[cfg(feature = "zstd")]
+            Coding::Zstd => "zstd",
+            Coding::Gzip => "gzip",
+            Coding::Deflate => "deflate",
+        }
+    }
+
+    fn from_str(coding: &str) -> Option<Self> {
+        match coding.trim() {
+            #[cfg(feature = "zstd")]
+            "zstd" => Some(Coding::Zstd),
+            "gzip" | "x-gzip" => Some(Coding::Gzip),
+            "deflate" => Some(Coding::Deflate),
+            _ => None,
+        }
+    }
+
+    pub fn compress(self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Coding::Zstd => zstd::encode_all(body, 0),
+            Coding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            },
+            Coding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            },
+        }
+    }
+
+    pub fn decompress(self, body: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.decoder(body)?.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    /// Like `decompress`, failing with `PayloadTooLarge` past `limit` bytes.
+    pub(crate) fn decompress_limited(self, body: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::new();
+        self.decoder(body)?.take(limit as u64 + 1).read_to_end(&mut out)?;
+        if out.len() > limit {
+            return Err(PayloadTooLarge { limit }.into());
+        }
+        Ok(out)
+    }
+
+    fn decoder<'a>(self, body: &'a [u8]) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            #[cfg(feature = "zstd")]
+            Coding::Zstd => Box::new(zstd::stream::read::Decoder::new(body)?),
+            Coding::Gzip => Box::new(GzDecoder::new(body)),
+            Coding::Deflate => Box::new(ZlibDecoder::new(body)),
+        })
+    }
+
+    /// The coding of a body, `None` if it isn't compressed.
+    pub(crate) fn of<H: HttpMessage>(msg: &H) -> io::Result<Option<Self>> {
+        match msg.headers().get(header::CONTENT_ENCODING).and_then(|ce| ce.to_str().ok()) {
+            None | Some("identity") => Ok(None),
+            Some(ce) => Coding::from_str(ce).map(Some).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("unsupported content encoding {:?}", ce))
+            }),
+        }
+    }
+}
+
+/// When to compress bodies. By default, nothing is compressed.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Compression {
+    /// The codings to use, most preferred first.
+    pub codings: Vec<Coding>,
+    /// Bodies smaller than this many bytes are sent as they are.
+    pub threshold: usize,
+}
+
+impl Compression {
+    /// Compress bodies of at least `threshold` bytes with `coding`.
+    pub fn new(coding: Coding, threshold: usize) -> Self {
+        Compression { codings: vec![coding], threshold }
+    }
+
+    /// The coding for a request body, if it should be compressed at all.
+    pub(crate) fn for_request(&self, body: &[u8]) -> Option<Coding> {
+        self.codings.first().cloned().filter(|_| body.len() >= self.threshold)
+    }
+
+    /// The coding for the response to `req`, out of those it accepts.
+    pub(crate) fn for_response<H: HttpMessage>(&self, req: &H, body: &[u8]) -> Option<Coding> {
+        if body.len() < self.threshold {
+            return None;
+        }
+        let accepted: Vec<Coding> = req.headers().get_all(header::ACCEPT_ENCODING).iter()
+            .filter_map(|ae| ae.to_str().ok())
+            .flat_map(|ae| ae.split(','))
+            // Ignore quality values, but not codings refused with `q=0`
+            .filter(|coding| !coding.replace(' ', "").ends_with(";q=0"))
+            .filter_map(|coding| Coding::from_str(coding.split(';').next().unwrap_or("")))
+            .collect();
+        self.codings.iter().cloned().find(|coding| accepted.contains(coding))
+    }
+}
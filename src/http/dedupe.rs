@@ -0,0 +1,116 @@
+//! Requests which may arrive more than once.
+//!
+//! Clients which retry a message, such as an `Outbox`, name it with an
+//! `Idempotency-Key` header. Servers remembering the keys they've seen reply
+//! to a retry with the response to the first request, instead of handling it again.
+
+use failure::{Error, Fail};
+use futures::{future, Future};
+use log::*;
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::MessageExt;
+
+pub(crate) const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// A request with the same idempotency key is still being handled.
+#[derive(Debug, Fail)]
+#[fail(display = "a request with idempotency key {:?} is already in progress", key)]
+pub struct InProgress {
+    pub key: String,
+}
+
+enum Seen {
+    InProgress,
+    /// The response, serialized.
+    Done(Vec<u8>),
+}
+
+struct State {
+    capacity: usize,
+    seen: HashMap<String, Seen>,
+    /// The keys of the responses, oldest first.
+    done: VecDeque<String>,
+}
+
+/// The responses to the latest requests with an idempotency key, shared between clones.
+///
+/// Only successful responses are kept, so failed requests are handled again.
+/// Requests arriving while one with the same key is handled fail with `InProgress`.
+#[derive(Clone)]
+pub struct Dedupe(Arc<Mutex<State>>);
+
+impl fmt::Debug for Dedupe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Dedupe").field(&self.0.lock().unwrap().seen.len()).finish()
+    }
+}
+
+impl Default for Dedupe {
+    fn default() -> Self {
+        Self::new(1024)
+    }
+}
+
+impl Dedupe {
+    /// Keep the responses to the last `capacity` keys.
+    pub fn new(capacity: usize) -> Self {
+        Dedupe(Arc::new(Mutex::new(State {
+            capacity,
+            seen: HashMap::new(),
+            done: VecDeque::new(),
+        })))
+    }
+
+    /// Handle the message named `key` with `handle`, unless it was already.
+    pub(crate) fn handle<M, F, R>(&self, key: String, handle: F) -> impl Future<Item=M::Response, Error=Error>
+        where M: MessageExt,
+              F: FnOnce() -> R,
+              R: Future<Item=M::Response, Error=Error>,
+    {
+        {
+            let mut state = self.0.lock().unwrap();
+            match state.seen.get(&key) {
+                Some(Seen::Done(body)) => {
+                    trace!("Replying to {:?} with the response to the first request", key);
+                    return future::Either::A(future::result(crate::deserialize(body)));
+                },
+                Some(Seen::InProgress) => return future::Either::A(future::err(InProgress { key }.into())),
+                None => {
+                    state.seen.insert(key.clone(), Seen::InProgress);
+                },
+            }
+        }
+        let dedupe = self.clone();
+        future::Either::B(handle().then(move |res| {
+            let body = match &res {
+                Ok(resp) => crate::serialize(resp).map_err(|err| warn!("Not keeping the response to {:?}: {}", key, err)).ok(),
+                Err(_) => None,
+            };
+            dedupe.finish(key, body);
+            res
+        }))
+    }
+
+    /// Keep the response to `key`, or forget it without one.
+    fn finish(&self, key: String, body: Option<Vec<u8>>) {
+        let mut state = self.0.lock().unwrap();
+        let body = match body {
+            Some(body) => body,
+            None => {
+                state.seen.remove(&key);
+                return;
+            },
+        };
+        state.seen.insert(key.clone(), Seen::Done(body));
+        state.done.push_back(key);
+        while state.done.len() > state.capacity {
+            if let Some(oldest) = state.done.pop_front() {
+                state.seen.remove(&oldest);
+            }
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! Body encodings understood by the HTTP endpoints.
+
+use actix_web::{error::PayloadError, http::header, HttpMessage};
+use failure::{Error, Fail};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// How message bodies are encoded on the wire. Requests carry their encoding
+/// in the `Content-Type` header, and responses use the same encoding as the request.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// `application/cbor`, used between `actix_directory` apps.
+    #[default]
+    Cbor,
+    /// `application/json`, for peers which don't speak CBOR.
+    Json,
+}
+
+impl Encoding {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Cbor => "application/cbor",
+            Encoding::Json => "application/json",
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Cbor => "cbor",
+            Encoding::Json => "json",
+        }
+    }
+
+    /// The encoding of a request or response.
+    /// Bodies without a `Content-Type` are assumed to be CBOR.
+    pub fn of<H: HttpMessage>(msg: &H) -> Self {
+        match msg.headers().get(header::CONTENT_TYPE).and_then(|ct| ct.to_str().ok()) {
+            Some(ct) if ct.contains("json") => Encoding::Json,
+            _ => Encoding::Cbor,
+        }
+    }
+
+    pub fn serialize<M: Serialize>(self, msg: &M) -> Result<Vec<u8>, Error> {
+        match self {
+            Encoding::Cbor => crate::serialize(msg),
+            Encoding::Json => serde_json::to_vec(msg).map_err(Error::from),
+        }
+    }
+
+    pub fn deserialize<M: DeserializeOwned>(self, body: &[u8]) -> Result<M, Error> {
+        match self {
+            Encoding::Cbor => crate::deserialize(body),
+            Encoding::Json => serde_json::from_slice(body).map_err(Error::from),
+        }
+    }
+}
+
+/// The body of a non-2xx response.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ErrorEnvelope {
+    pub error: String,
+}
+
+/// A remote server failed to handle a request.
+#[derive(Debug, Fail)]
+#[fail(display = "remote error ({}): {}", status, message)]
+pub struct RemoteError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl RemoteError {
+    /// Recover the error from a failed response, falling back to the raw
+    /// body if it isn't an `ErrorEnvelope`.
+    pub(crate) fn from_body(status: u16, encoding: Encoding, body: &[u8]) -> Self {
+        let message = encoding.deserialize::<ErrorEnvelope>(body)
+            .map(|env| env.error)
+            .unwrap_or_else(|_| String::from_utf8_lossy(body).into_owned());
+        RemoteError { status, message }
+    }
+}
+
+/// The largest body read by default, as in actix-web.
+pub const DEFAULT_LIMIT: usize = 256 * 1024;
+
+/// A body was larger than allowed.
+#[derive(Debug, Fail)]
+#[fail(display = "payload too large, the limit is {} bytes", limit)]
+pub struct PayloadTooLarge {
+    pub limit: usize,
+}
+
+/// The error for `err` while reading a body of at most `limit` bytes.
+pub(crate) fn payload_error(err: PayloadError, limit: usize) -> Error {
+    match err {
+        PayloadError::Overflow => PayloadTooLarge { limit }.into(),
+        err => err.into(),
+    }
+}
@@ -0,0 +1,307 @@
+//! Controlling the servers started by an `App`.
+
+use actix::prelude::*;
+#[cfg(unix)]
+use actix_net::service::{NewService, Service};
+#[cfg(unix)]
+use actix_web::Extensions;
+#[cfg(unix)]
+use actix_web::server::{HttpService, IoStream, ServiceConfig};
+use actix_web::server::{IntoHttpHandler, StopServer};
+use failure::{err_msg, Error};
+use futures::{future, future::Loop, sync::oneshot, Future, Stream};
+use log::*;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::timer::Delay;
+
+use std::fmt;
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::net::Shutdown;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::router::{InFlight, Remote};
+#[cfg(unix)]
+use super::peer::{PeerCheck, PeerCred, PeerPolicy};
+
+/// How long a graceful stop waits for open connections to close.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where a server accepts connections.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    #[cfg(feature = "tls")]
+    Https(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "http://{}", addr),
+            #[cfg(feature = "tls")]
+            ListenAddr::Https(addr) => write!(f, "https://{}", addr),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Lets other apps talk to the server.
+impl From<ListenAddr> for Remote {
+    fn from(other: ListenAddr) -> Remote {
+        match other {
+            ListenAddr::Tcp(addr) => Remote::Http(url::Url::parse(&format!("http://{}/", addr)).unwrap()),
+            #[cfg(feature = "tls")]
+            ListenAddr::Https(addr) => Remote::Http(url::Url::parse(&format!("https://{}/", addr)).unwrap()),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => Remote::LocalHttp(path),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Stopper {
+    Tcp(Recipient<StopServer>),
+    #[cfg(unix)]
+    Unix {
+        accept: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+        connections: Arc<AtomicUsize>,
+        /// Remove the socket once stopped.
+        remove: bool,
+    },
+}
+
+/// A running server. Dropping the handle leaves the server running.
+#[derive(Clone)]
+pub struct ServerHandle {
+    addr: ListenAddr,
+    stopper: Stopper,
+    stopped: future::Shared<oneshot::Receiver<()>>,
+    done: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+}
+
+impl fmt::Debug for ServerHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ServerHandle").field("addr", &self.addr).finish()
+    }
+}
+
+impl ServerHandle {
+    fn new(addr: ListenAddr, stopper: Stopper) -> Self {
+        let (tx, rx) = oneshot::channel();
+        ServerHandle {
+            addr, stopper,
+            stopped: rx.shared(),
+            done: Arc::new(Mutex::new(Some(tx))),
+        }
+    }
+
+    /// The address the server is listening on.
+    pub fn addr(&self) -> &ListenAddr {
+        &self.addr
+    }
+
+    /// Stop accepting connections, and resolve once the open ones are
+    /// closed, or after 30 seconds.
+    pub fn stop(&self) -> impl Future<Item=(), Error=Error> {
+        let done = self.done.clone();
+        let stopping = match &self.stopper {
+            Stopper::Tcp(server) => future::Either::A(
+                server.send(StopServer { graceful: true })
+                      .map_err(Error::from)
+                      .and_then(|res| res.map_err(|()| err_msg("failed to stop server")))
+            ),
+            #[cfg(unix)]
+            Stopper::Unix { accept, connections, remove } => {
+                if let Some(tx) = accept.lock().unwrap().take() {
+                    let _ = tx.send(());
+                }
+                let path = match &self.addr {
+                    ListenAddr::Unix(path) if *remove => Some(path.clone()),
+                    _ => None,
+                };
+                future::Either::B(drain(connections.clone()).map(move |()| {
+                    if let Some(path) = path {
+                        let _ = std::fs::remove_file(path);
+                    }
+                }))
+            },
+        };
+        stopping.then(move |res| {
+            if let Some(tx) = done.lock().unwrap().take() {
+                let _ = tx.send(());
+            }
+            res
+        })
+    }
+
+    /// Leave the socket in place when stopping, as it belongs to someone else.
+    #[cfg(unix)]
+    pub(crate) fn keep_socket(mut self) -> Self {
+        if let Stopper::Unix { ref mut remove, .. } = self.stopper {
+            *remove = false;
+        }
+        self
+    }
+
+    /// Resolves once the server has been stopped.
+    pub fn join(&self) -> impl Future<Item=(), Error=Error> {
+        self.stopped.clone().map(|_| ()).map_err(Error::from)
+    }
+}
+
+/// Wait for `connections` to be closed.
+fn drain(connections: Arc<AtomicUsize>) -> impl Future<Item=(), Error=Error> {
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    future::loop_fn((), move |()| {
+        if connections.load(Ordering::SeqCst) == 0 || Instant::now() >= deadline {
+            return future::Either::A(future::ok(Loop::Break(())));
+        }
+        future::Either::B(Delay::new(Instant::now() + Duration::from_millis(50))
+            .map(|()| Loop::Continue(()))
+            .map_err(Error::from))
+    })
+}
+
+/// Start an HTTP server on an already bound TCP server.
+pub(crate) fn serve_tcp<H, F>(server: actix_web::server::HttpServer<H, F>) -> ServerHandle
+    where
+        F: Fn() -> H + Send + Clone + 'static,
+        H: IntoHttpHandler + 'static,
+{
+    start_tcp(server, ListenAddr::Tcp)
+}
+
+/// Start an HTTPS server on a TCP server bound with `bind_tls`.
+#[cfg(feature = "tls")]
+pub(crate) fn serve_tls<H, F>(server: actix_web::server::HttpServer<H, F>) -> ServerHandle
+    where
+        F: Fn() -> H + Send + Clone + 'static,
+        H: IntoHttpHandler + 'static,
+{
+    start_tcp(server, ListenAddr::Https)
+}
+
+fn start_tcp<H, F>(server: actix_web::server::HttpServer<H, F>, addr: fn(SocketAddr) -> ListenAddr) -> ServerHandle
+    where
+        F: Fn() -> H + Send + Clone + 'static,
+        H: IntoHttpHandler + 'static,
+{
+    let bound = server.addrs()[0];
+    let server = server.disable_signals().start();
+    ServerHandle::new(addr(bound), Stopper::Tcp(server.recipient()))
+}
+
+/// Start an HTTP server accepting connections on `listener`, bound at `path`.
+///
+/// Each connection carries the credentials of the process which opened it,
+/// for `PeerCheck` to check its requests against.
+#[cfg(unix)]
+pub(crate) fn serve_unix<S, F>(factory: F, listener: tokio_uds::UnixListener, path: PathBuf, policy: Option<PeerPolicy>) -> ServerHandle
+    where
+        F: FnOnce() -> actix_web::App<S> + 'static,
+        S: 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    // Dropping the handle without stopping leaves the server running
+    let stop = rx.or_else(|_| future::empty::<(), oneshot::Canceled>())
+                 .into_stream()
+                 .map(|()| None)
+                 .map_err(|_| ());
+    let incoming = listener.incoming()
+        .then(|res| Ok(res.map_err(|err| error!("Failed to accept connection: {}", err)).ok()))
+        .filter_map(|stream| stream)
+        .map(Some)
+        .select(stop)
+        .take_while(|stream| Ok(stream.is_some()))
+        .filter_map(|stream| stream);
+    let handler = factory().middleware(PeerCheck { policy }).into_handler();
+    let service = HttpService::new(ServiceConfig::build(handler).finish());
+    Arbiter::spawn(service.new_service().and_then(move |mut service| incoming.for_each(move |stream| {
+        let mut extensions = Extensions::new();
+        match PeerCred::of(&stream) {
+            Ok(peer) => extensions.insert(peer),
+            Err(err) => warn!("Failed to get peer credentials: {}", err),
+        }
+        let conn = Connection { io: stream, extensions: Rc::new(extensions), _guard: InFlight::new(&counter) };
+        Arbiter::spawn(service.call(conn).map_err(|err| debug!("Connection closed with error: {:?}", err)));
+        Ok(())
+    })));
+    ServerHandle::new(ListenAddr::Unix(path), Stopper::Unix {
+        accept: Arc::new(Mutex::new(Some(tx))),
+        connections,
+        remove: true,
+    })
+}
+
+/// A connection to a Unix socket, counted as open until dropped, with
+/// extensions passed on to each of its requests.
+#[cfg(unix)]
+struct Connection {
+    io: tokio_uds::UnixStream,
+    extensions: Rc<Extensions>,
+    _guard: InFlight,
+}
+
+#[cfg(unix)]
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.io.read(buf)
+    }
+}
+
+#[cfg(unix)]
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+#[cfg(unix)]
+impl AsyncRead for Connection {}
+
+#[cfg(unix)]
+impl AsyncWrite for Connection {
+    fn shutdown(&mut self) -> futures::Poll<(), io::Error> {
+        AsyncWrite::shutdown(&mut self.io)
+    }
+}
+
+#[cfg(unix)]
+impl IoStream for Connection {
+    fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        self.io.shutdown(how)
+    }
+
+    fn set_nodelay(&mut self, _nodelay: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_linger(&mut self, _dur: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_keepalive(&mut self, _dur: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn extensions(&self) -> Option<Rc<Extensions>> {
+        Some(self.extensions.clone())
+    }
+}
@@ -1,10 +1,37 @@
 //! Run the `Service` as an HTTP endpoint.
 
+pub(crate) mod attachment;
+mod batch;
 mod client;
+mod compression;
+mod dedupe;
+mod encoding;
+mod handle;
+#[cfg(unix)]
+mod peer;
+mod pool;
 mod server;
+#[cfg(feature = "tls")]
+mod tls;
 
 use failure::Error;
 
+pub use self::attachment::{Attachment, TooManyAttachments};
+pub use self::batch::{BatchConfig, TooManyEntries};
+pub(crate) use self::batch::{Batch, Batcher, Outcome};
 pub use self::client::*;
-pub use self::server::HttpApp;
+pub use self::compression::{Coding, Compression};
+pub use self::dedupe::{Dedupe, InProgress};
+pub(crate) use self::dedupe::IDEMPOTENCY_KEY;
+pub use self::encoding::{Encoding, ErrorEnvelope, PayloadTooLarge, RemoteError, DEFAULT_LIMIT};
+pub(crate) use self::encoding::payload_error;
+pub use self::handle::{ListenAddr, ServerHandle};
+pub use self::pool::{PoolConfig, PoolStats};
+pub(crate) use self::pool::Pool;
+#[cfg(unix)]
+pub use self::peer::{PeerCred, PeerDenied, PeerPolicy};
+pub(crate) use self::handle::*;
+pub use self::server::{ExposeConfig, HttpApp};
+#[cfg(feature = "tls")]
+pub use self::tls::{TlsConfig, TlsIdentity};
 pub(crate) use self::server::*;
@@ -0,0 +1,124 @@
+//! Authorizing local clients by the credentials of their process.
+
+use actix_web::{http::StatusCode, middleware::{Middleware, Started}, HttpRequest};
+use failure::{Error, Fail};
+use log::*;
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use super::Encoding;
+
+/// The process at the other end of a Unix socket, as reported by the kernel.
+///
+/// Requests served by `serve_local_http` carry it in `HttpRequest::extensions`,
+/// for middleware and raw HTTP handlers. Message handlers get it through
+/// `MessageExt::set_peer`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeerCred {
+    /// Only known on Linux.
+    pub pid: Option<u32>,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl fmt::Display for PeerCred {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "pid {} (uid {}, gid {})", pid, self.uid, self.gid),
+            None => write!(f, "uid {}, gid {}", self.uid, self.gid),
+        }
+    }
+}
+
+impl PeerCred {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) fn of(stream: &tokio_uds::UnixStream) -> io::Result<Self> {
+        use std::os::unix::io::AsRawFd;
+        let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED,
+                             &mut cred as *mut libc::ucred as *mut libc::c_void, &mut len)
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PeerCred { pid: Some(cred.pid as u32), uid: cred.uid, gid: cred.gid })
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub(crate) fn of(stream: &tokio_uds::UnixStream) -> io::Result<Self> {
+        stream.peer_cred().map(|cred| PeerCred { pid: None, uid: cred.uid, gid: cred.gid })
+    }
+}
+
+/// Which local processes may send messages through `serve_local_http`.
+#[derive(Clone)]
+pub enum PeerPolicy {
+    /// Peers running as one of `uids`, or in one of `gids`.
+    Allow { uids: Vec<u32>, gids: Vec<u32> },
+    /// Peers for which the callback returns `true`.
+    Callback(Arc<dyn Fn(&PeerCred) -> bool + Send + Sync>),
+}
+
+impl fmt::Debug for PeerPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerPolicy::Allow { uids, gids } => f.debug_struct("Allow").field("uids", uids).field("gids", gids).finish(),
+            PeerPolicy::Callback(_) => f.write_str("Callback"),
+        }
+    }
+}
+
+impl PeerPolicy {
+    pub fn callback<F>(f: F) -> Self
+        where F: 'static + Fn(&PeerCred) -> bool + Send + Sync
+    {
+        PeerPolicy::Callback(Arc::new(f))
+    }
+
+    pub fn allows(&self, peer: &PeerCred) -> bool {
+        match self {
+            PeerPolicy::Allow { uids, gids } => uids.contains(&peer.uid) || gids.contains(&peer.gid),
+            PeerPolicy::Callback(f) => f(peer),
+        }
+    }
+}
+
+/// A local process was refused by the `PeerPolicy`.
+#[derive(Debug, Fail)]
+#[fail(display = "peer {} is not allowed", _0)]
+pub struct PeerDenied(pub String);
+
+/// Makes the credentials of the connection's peer available in the request
+/// extensions, and rejects requests from peers the policy doesn't allow.
+pub(crate) struct PeerCheck {
+    pub(crate) policy: Option<PeerPolicy>,
+}
+
+impl<S: 'static> Middleware<S> for PeerCheck {
+    fn start(&self, req: &HttpRequest<S>) -> actix_web::Result<Started> {
+        let peer = req.stream_extensions().and_then(|ext| ext.get::<PeerCred>()).cloned();
+        if let Some(peer) = peer {
+            req.extensions_mut().insert(peer);
+        }
+        let policy = match &self.policy {
+            Some(policy) => policy,
+            None => return Ok(Started::Done),
+        };
+        let denied = match &peer {
+            Some(peer) if policy.allows(peer) => {
+                debug!("Request from {} on {}", peer, req.path());
+                return Ok(Started::Done);
+            },
+            Some(peer) => PeerDenied(peer.to_string()),
+            None => PeerDenied("with unknown credentials".to_string()),
+        };
+        warn!("Rejecting request on {}: {}", req.path(), denied);
+        let mut resp = super::error_response(Encoding::of(req), &Error::from(denied));
+        *resp.status_mut() = StatusCode::FORBIDDEN;
+        Ok(Started::Response(resp))
+    }
+}
@@ -0,0 +1,383 @@
+//! Reusing connections to a remote between requests.
+//!
+//! HTTP remotes get their own `ClientConnector`, which already pools TCP
+//! connections. actix-web can't pool connections it didn't open itself, so
+//! Unix socket connections are handed back here once a request is done.
+
+use actix::prelude::*;
+use actix_web::client::{ClientConnector, ClientConnectorStats};
+use failure::Error;
+use log::*;
+
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use super::ClientConfig;
+
+/// How connections to a remote are reused.
+#[derive(Clone, Copy, Debug)]
+pub struct PoolConfig {
+    /// Most connections open to the remote at once, busy or idle.
+    /// Further requests wait for one to be free. 0 means no limit.
+    pub size: usize,
+    /// How long an unused connection is kept open. This should be shorter than
+    /// the server's keep-alive, which is 5 seconds for actix-web.
+    pub idle_timeout: Duration,
+    /// Keep connections open between requests at all.
+    pub keep_alive: bool,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            size: 100,
+            idle_timeout: Duration::from_secs(4),
+            keep_alive: true,
+        }
+    }
+}
+
+/// Connection counts for a remote, since it was first used.
+///
+/// For HTTP remotes they are reported by the `ClientConnector` once a second.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PoolStats {
+    pub opened: usize,
+    /// Requests sent on a connection left open by an earlier request.
+    pub reused: usize,
+    pub closed: usize,
+    /// Connections which failed to open.
+    pub errors: usize,
+    /// Requests currently waiting for a connection.
+    pub waiting: usize,
+}
+
+/// The connections to one remote, shared by clones.
+#[derive(Clone, Default)]
+pub(crate) struct Pool(Arc<Mutex<State>>);
+
+#[derive(Default)]
+struct State {
+    stats: PoolStats,
+    /// Started on first use, since there may be no running system before.
+    connector: Option<Addr<ClientConnector>>,
+    #[cfg(unix)]
+    local: local::Local,
+}
+
+impl std::fmt::Debug for Pool {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_tuple("Pool").field(&self.stats()).finish()
+    }
+}
+
+impl Pool {
+    pub(crate) fn stats(&self) -> PoolStats {
+        self.0.lock().unwrap().stats
+    }
+
+    /// The connector for HTTP requests to the remote.
+    pub(crate) fn connector(&self, config: &ClientConfig) -> Result<Addr<ClientConnector>, Error> {
+        let mut state = self.0.lock().unwrap();
+        if let Some(connector) = &state.connector {
+            return Ok(connector.clone());
+        }
+        let stats = StatsCollector(Arc::downgrade(&self.0)).start().recipient();
+        let connector = configure(new_connector(config)?, &config.pool).stats(stats).start();
+        state.connector = Some(connector.clone());
+        Ok(connector)
+    }
+}
+
+/// A connector for `config`'s TLS settings.
+pub(crate) fn new_connector(config: &ClientConfig) -> Result<ClientConnector, Error> {
+    #[cfg(feature = "tls")]
+    {
+        if let Some(tls) = &config.tls {
+            return Ok(ClientConnector::with_connector(tls.connector()?.into()));
+        }
+    }
+    #[cfg(not(feature = "tls"))]
+    let _ = config;
+    Ok(ClientConnector::default())
+}
+
+fn configure(connector: ClientConnector, config: &PoolConfig) -> ClientConnector {
+    let connector = connector.limit(config.size).conn_keep_alive(config.idle_timeout);
+    if config.keep_alive {
+        connector
+    } else {
+        // Connections past their lifetime are closed when released
+        connector.conn_lifetime(Duration::from_secs(0))
+    }
+}
+
+/// Adds up the stats reported by a pool's `ClientConnector`.
+struct StatsCollector(Weak<Mutex<State>>);
+
+impl Actor for StatsCollector {
+    type Context = Context<Self>;
+}
+
+impl Handler<ClientConnectorStats> for StatsCollector {
+    type Result = ();
+
+    fn handle(&mut self, msg: ClientConnectorStats, ctxt: &mut Context<Self>) {
+        let state = match self.0.upgrade() {
+            Some(state) => state,
+            None => return ctxt.stop(),
+        };
+        let stats = &mut state.lock().unwrap().stats;
+        stats.opened += msg.opened;
+        stats.reused += msg.reused;
+        stats.closed += msg.closed;
+        stats.errors += msg.errors + msg.timeouts;
+        stats.waiting = msg.wait_queue;
+        trace!("Connection pool stats: {:?}", stats);
+    }
+}
+
+#[cfg(unix)]
+mod local {
+    use actix::Arbiter;
+    use actix_web::{client::Connection, server::IoStream};
+    use failure::Error;
+    use futures::{future, sync::oneshot, Future};
+    use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio_uds::UnixStream;
+
+    use std::collections::VecDeque;
+    use std::io::{self, Read, Write};
+    use std::net::Shutdown;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+
+    use super::{Pool, PoolConfig};
+
+    /// The Unix socket connections of a pool.
+    #[derive(Default)]
+    pub(super) struct Local {
+        /// Unused connections, and since when, most recently used last.
+        idle: Vec<(UnixStream, Instant)>,
+        /// Connections currently open, busy or idle.
+        open: usize,
+        /// Requests waiting for a connection to be freed. They get `None`
+        /// when they may open a new one.
+        waiters: VecDeque<oneshot::Sender<Option<UnixStream>>>,
+    }
+
+    enum Slot {
+        Idle(UnixStream),
+        New,
+        Wait(oneshot::Receiver<Option<UnixStream>>),
+    }
+
+    /// Whether the server is still there, and hasn't sent anything unexpected.
+    fn is_alive(io: &UnixStream) -> bool {
+        let mut buf = [0u8; 1];
+        let read = unsafe {
+            libc::recv(io.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, 1,
+                       libc::MSG_PEEK | libc::MSG_DONTWAIT)
+        };
+        read < 0 && io::Error::last_os_error().kind() == io::ErrorKind::WouldBlock
+    }
+
+    impl Pool {
+        fn acquire(&self, config: &PoolConfig) -> Slot {
+            let mut state = self.0.lock().unwrap();
+            let now = Instant::now();
+            while let Some((io, since)) = state.local.idle.pop() {
+                if now.duration_since(since) <= config.idle_timeout && is_alive(&io) {
+                    state.stats.reused += 1;
+                    return Slot::Idle(io);
+                }
+                state.stats.closed += 1;
+                state.local.open -= 1;
+            }
+            if config.size == 0 || state.local.open < config.size {
+                state.local.open += 1;
+                return Slot::New;
+            }
+            let (tx, rx) = oneshot::channel();
+            state.local.waiters.push_back(tx);
+            state.stats.waiting = state.local.waiters.len();
+            Slot::Wait(rx)
+        }
+
+        /// Let the next waiting request use `io`, or keep it for later.
+        fn put(&self, mut io: UnixStream) {
+            let mut state = self.0.lock().unwrap();
+            while let Some(waiter) = state.local.waiters.pop_front() {
+                match waiter.send(Some(io)) {
+                    Ok(()) => {
+                        state.stats.reused += 1;
+                        state.stats.waiting = state.local.waiters.len();
+                        return;
+                    },
+                    Err(back) => io = back.unwrap(),
+                }
+            }
+            state.stats.waiting = 0;
+            state.local.idle.push((io, Instant::now()));
+        }
+
+        /// A connection was closed, or failed to open.
+        fn closed(&self, opened: bool) {
+            let mut state = self.0.lock().unwrap();
+            if opened {
+                state.stats.closed += 1;
+            } else {
+                state.stats.errors += 1;
+            }
+            state.local.open -= 1;
+            // Hand the free slot to a waiting request
+            while let Some(waiter) = state.local.waiters.pop_front() {
+                if waiter.send(None).is_ok() {
+                    state.local.open += 1;
+                    break;
+                }
+            }
+            state.stats.waiting = state.local.waiters.len();
+        }
+
+        fn open(&self, path: &Path) -> impl Future<Item=UnixStream, Error=Error> {
+            let pool = self.clone();
+            crate::socket::connect(path).then(move |res| {
+                match res {
+                    Ok(_) => pool.0.lock().unwrap().stats.opened += 1,
+                    Err(_) => pool.closed(false),
+                }
+                res.map_err(Error::from)
+            })
+        }
+
+        /// A connection to the socket at `path`, and the lease to
+        /// return it once the response has been read.
+        pub(crate) fn get(&self, path: &Path, config: PoolConfig) -> impl Future<Item=(Connection, Lease), Error=Error> {
+            let io = match self.acquire(&config) {
+                Slot::Idle(io) => future::Either::A(future::ok(io)),
+                Slot::New => future::Either::B(future::Either::A(self.open(path))),
+                Slot::Wait(rx) => {
+                    let (pool, path) = (self.clone(), path.to_path_buf());
+                    future::Either::B(future::Either::B(rx.map_err(Error::from).and_then(move |io| match io {
+                        Some(io) => future::Either::A(future::ok(io)),
+                        None => future::Either::B(pool.open(&path)),
+                    })))
+                },
+            };
+            let pool = self.clone();
+            io.map(move |io| {
+                let (tx, rx) = oneshot::channel();
+                let stream = Pooled { io: Some(io), returned: Some(tx), pool: pool.clone() };
+                (Connection::from_stream(stream), Lease { returned: Some(rx), pool, config })
+            })
+        }
+    }
+
+    /// Gets the connection back from actix-web once it's done with it.
+    pub(crate) struct Lease {
+        returned: Option<oneshot::Receiver<UnixStream>>,
+        pool: Pool,
+        config: PoolConfig,
+    }
+
+    impl Lease {
+        /// Reuse the connection if the request succeeded, otherwise close it.
+        pub(crate) fn release(mut self, reusable: bool) {
+            let (returned, pool, config) = match self.returned.take() {
+                Some(returned) => (returned, self.pool.clone(), self.config),
+                None => return,
+            };
+            Arbiter::spawn(returned.then(move |io| {
+                if let Ok(io) = io {
+                    if reusable && config.keep_alive && config.idle_timeout > Duration::from_secs(0) {
+                        pool.put(io);
+                    } else {
+                        drop(io);
+                        pool.closed(true);
+                    }
+                }
+                Ok(())
+            }));
+        }
+    }
+
+    impl Drop for Lease {
+        fn drop(&mut self) {
+            // Not released, the connection can't be trusted anymore
+            if let Some(mut returned) = self.returned.take() {
+                if let Ok(Some(io)) = returned.try_recv() {
+                    drop(io);
+                    self.pool.closed(true);
+                }
+            }
+        }
+    }
+
+    /// A pooled connection, handed back to its `Lease` when dropped.
+    struct Pooled {
+        io: Option<UnixStream>,
+        returned: Option<oneshot::Sender<UnixStream>>,
+        pool: Pool,
+    }
+
+    impl Pooled {
+        fn io(&mut self) -> &mut UnixStream {
+            self.io.as_mut().unwrap()
+        }
+    }
+
+    impl Drop for Pooled {
+        fn drop(&mut self) {
+            if let (Some(io), Some(tx)) = (self.io.take(), self.returned.take()) {
+                if tx.send(io).is_err() {
+                    // The request was abandoned
+                    self.pool.closed(true);
+                }
+            }
+        }
+    }
+
+    impl Read for Pooled {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.io().read(buf)
+        }
+    }
+
+    impl Write for Pooled {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.io().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.io().flush()
+        }
+    }
+
+    impl AsyncRead for Pooled {}
+
+    impl AsyncWrite for Pooled {
+        fn shutdown(&mut self) -> futures::Poll<(), io::Error> {
+            AsyncWrite::shutdown(self.io())
+        }
+    }
+
+    impl IoStream for Pooled {
+        fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+            IoStream::shutdown(self.io(), how)
+        }
+
+        fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+            IoStream::set_nodelay(self.io(), nodelay)
+        }
+
+        fn set_linger(&mut self, dur: Option<Duration>) -> io::Result<()> {
+            IoStream::set_linger(self.io(), dur)
+        }
+
+        fn set_keepalive(&mut self, dur: Option<Duration>) -> io::Result<()> {
+            IoStream::set_keepalive(self.io(), dur)
+        }
+    }
+}
@@ -1,16 +1,51 @@
 use actix::Addr;
-use actix_web::{http, App, AsyncResponder, HttpMessage, HttpRequest, HttpResponse};
+use actix_web::{http::{self, header, ContentEncoding, StatusCode}, App, AsyncResponder, FutureResponse, HttpMessage, HttpRequest, HttpResponse};
 use failure::Error;
 use futures::{future, Future};
 use log::*;
 
 use crate::{MessageExt, RouteType};
 use crate::app;
+use super::{attachment::{self, Body}, batch, Coding, Compression, Encoding, ErrorEnvelope, PayloadTooLarge};
 
-use crate::{deserialize, serialize};
+use std::sync::Arc;
+
+/// Sent by clients which don't wait for the reply to a message, as `Prefer: respond-async`.
+pub(crate) const PREFER: &str = "prefer";
+pub(crate) const RESPOND_ASYNC: &str = "respond-async";
 
 type AdApp<A> = App<Addr<A>>;
-type AppFactory<A> = fn(AdApp<A>, Option<RouteType>) -> AdApp<A>;
+type AppFactory<A> = fn(AdApp<A>, Option<RouteType>, &ExposeConfig) -> AdApp<A>;
+
+/// How an exposed message is served.
+#[derive(Clone, Debug)]
+pub struct ExposeConfig {
+    /// Which responses are compressed, for clients accepting it.
+    pub compression: Compression,
+    /// Largest request accepted, in bytes, after decompression.
+    /// Larger requests get a `413 Payload Too Large`.
+    /// Attachments don't count towards it, since they aren't buffered.
+    pub limit: usize,
+    /// Limits for the requests, until their reply is ready. Requests turned
+    /// away get a `503 Service Unavailable`.
+    pub limiter: Option<crate::Limiter>,
+    /// Remembers the responses to requests with an `Idempotency-Key`, so
+    /// retries are only handled once. Retries of a request still being
+    /// handled get a `409 Conflict`. Requests which `Prefer: respond-async`
+    /// aren't deduplicated.
+    pub dedupe: Option<super::Dedupe>,
+}
+
+impl Default for ExposeConfig {
+    fn default() -> Self {
+        ExposeConfig {
+            compression: Compression::default(),
+            limit: super::DEFAULT_LIMIT,
+            limiter: None,
+            dedupe: None,
+        }
+    }
+}
 
 
 /// Used to create a list of functions to apply to a `actix_web::App`
@@ -19,7 +54,9 @@ type AppFactory<A> = fn(AdApp<A>, Option<RouteType>) -> AdApp<A>;
 pub struct HttpFactory<A>
     where A: actix::Actor
 {
-    pub factory: Vec<(AppFactory<A>, Option<RouteType>)>,
+    pub factory: Vec<(&'static str, AppFactory<A>, Option<RouteType>, ExposeConfig)>,
+    /// The same messages, when they are sent in a batch.
+    batch: batch::Handlers<A>,
 }
 
 impl<A> Clone for HttpFactory<A>
@@ -27,12 +64,13 @@ impl<A> Clone for HttpFactory<A>
 {
     fn clone(&self) -> Self {
         HttpFactory {
-            factory: self.factory.clone()
+            factory: self.factory.clone(),
+            batch: self.batch.clone(),
         }
     }
 }
 
-fn message<M, H>(mut app: H, ty: Option<RouteType>) -> H
+fn message<M, H>(mut app: H, ty: Option<RouteType>, config: &ExposeConfig) -> H
     where
         M: MessageExt,
         H: HttpApp
@@ -40,7 +78,7 @@ fn message<M, H>(mut app: H, ty: Option<RouteType>) -> H
     let path = format!("/{}", M::PATH);
     trace!("Exposing message {:?} on path: {:?}", crate::get_type!(M), path);
     app = match ty {
-        _  => app.message::<M>(&path),
+        _  => app.message_with::<M>(&path, config.clone()),
         
     };
     app
@@ -48,27 +86,55 @@ fn message<M, H>(mut app: H, ty: Option<RouteType>) -> H
 }
 
 impl<A> HttpFactory<A>
-    where A: actix::Actor,
+    where A: actix::Actor<Context=actix::Context<A>>,
           AdApp<A>: HttpApp
 {
     pub fn new() -> Self {
         HttpFactory {
             factory: Vec::new(),
+            batch: Default::default(),
         }
     }
 
-    pub fn route<M: MessageExt>(&mut self, ty: Option<RouteType>) {
-        self.factory.push((message::<M, AdApp<A>>, ty));
+    pub fn route<M: MessageExt>(&mut self, ty: Option<RouteType>)
+        where A: actix::Handler<M>
+    {
+        self.route_with::<M>(ty, ExposeConfig::default());
+    }
+
+    /// Expose `M`, replacing any earlier configuration for it.
+    pub fn route_with<M: MessageExt>(&mut self, ty: Option<RouteType>, config: ExposeConfig)
+        where A: actix::Handler<M>
+    {
+        self.factory.retain(|(path, ..)| *path != M::PATH);
+        if M::PATH == <batch::Batch as MessageExt>::PATH {
+            warn!("Exposing {:?} on the path of the batch endpoint, which won't be served", crate::get_type!(M));
+        }
+        self.batch.insert(M::PATH, batch::BatchRoute {
+            handler: batch::handle_entry::<M, A>,
+            limit: config.limit,
+            limiter: config.limiter.clone(),
+        });
+        self.factory.push((M::PATH, message::<M, AdApp<A>>, ty, config));
     }
 
+    /// Add the routes of the exposed messages to `app`, and the batch
+    /// endpoint serving them if there are any.
     pub fn configure(&self, app: AdApp<A>) -> AdApp<A> {
         let mut app = app;
         let f: HttpFactory<A> = self.clone();
-        let factory: Vec<(AppFactory<A>, Option<RouteType>)> = f.factory;
-        for (f, ty) in factory.into_iter() {
-            app = f(app, ty);
+        let batch_path = <batch::Batch as MessageExt>::PATH;
+        let clashes = f.batch.contains_key(batch_path);
+        let factory: Vec<(&'static str, AppFactory<A>, Option<RouteType>, ExposeConfig)> = f.factory;
+        for (_, f, ty, config) in factory.into_iter() {
+            app = f(app, ty, &config);
         }
-        app
+        if f.batch.is_empty() || clashes {
+            return app;
+        }
+        let limit = batch::limit(&f.batch);
+        let handlers = Arc::new(f.batch);
+        app.route(&format!("/{}", batch_path), http::Method::POST, move |req| batch::handle_batch(req, &handlers, limit))
     }
 }
 
@@ -76,13 +142,21 @@ impl<A> HttpFactory<A>
 /// by adding the method `message`.
 ///
 /// TODO: This should really use content-encoding to differentiate between json/bincode/etc.
-pub trait HttpApp {
+pub trait HttpApp: Sized {
 
 	/// Register the path `path` as able to respond to requests for the
 	/// message type `M`.
 	/// Since this will use the `Addr<Service>` in the `App` state,
 	/// this handler must have been previously registered.
 	fn message<M>(self, path: &str) -> Self
+		where
+		    M: MessageExt,
+	{
+	    self.message_with::<M>(path, ExposeConfig::default())
+	}
+
+	/// Like `message`, serving the message as configured.
+	fn message_with<M>(self, path: &str, config: ExposeConfig) -> Self
 		where
 		    M: MessageExt;
 
@@ -92,12 +166,12 @@ pub trait HttpApp {
 }
 
 impl HttpApp for App<Addr<app::ServerIn>> {
-	fn message<M>(self, path: &str) -> Self
-		where
-		    M: MessageExt,
-	{
-		self.route(path, http::Method::POST, handle_request::<M, app::ServerIn>)
-	}
+    fn message_with<M>(self, path: &str, config: ExposeConfig) -> Self
+        where
+            M: MessageExt,
+    {
+        self.route(path, http::Method::POST, move |req| handle_request::<M, app::ServerIn>(req, &config))
+    }
 
     fn jmessage<M>(self, path: &str) -> Self
         where
@@ -108,11 +182,11 @@ impl HttpApp for App<Addr<app::ServerIn>> {
 }
 
 impl HttpApp for App<Addr<app::ClientIn>> {
-    fn message<M>(self, path: &str) -> Self
+    fn message_with<M>(self, path: &str, config: ExposeConfig) -> Self
         where
             M: MessageExt,
     {
-        self.route(path, http::Method::POST, handle_request::<M, app::ClientIn>)
+        self.route(path, http::Method::POST, move |req| handle_request::<M, app::ClientIn>(req, &config))
     }
 
     fn jmessage<M>(self, path: &str) -> Self
@@ -160,34 +234,191 @@ fn handle_json_request<M, A>(
 }
 
 /// Simple wrapper function. Deserialize request, and serialize the output.
+///
+/// The request's `Content-Type` decides how the body is decoded, and the
+/// response is encoded the same way. Failures are reported as an `ErrorEnvelope`.
+///
+/// Requests which `Prefer: respond-async` get a `202 Accepted` as soon as
+/// the message is decoded, and its reply is dropped.
 fn handle_request<M, A>(
-    req: HttpRequest<Addr<A>>
+    req: HttpRequest<Addr<A>>,
+    config: &ExposeConfig,
 ) -> impl actix_web::Responder
 	where
         A: actix::Actor<Context=actix::Context<A>> + actix::Handler<M>,
 	    M: 'static + MessageExt
+{
+    let limiter = match &config.limiter {
+        Some(limiter) => limiter.clone(),
+        None => return respond::<M, A>(req, config, None),
+    };
+    let (encoding, config) = (Encoding::of(&req), config.clone());
+    limiter.acquire().then(move |permit| match permit {
+        Ok(permit) => future::Either::A(respond::<M, A>(req, &config, Some(permit))),
+        Err(err) => future::Either::B(future::ok(error_response(encoding, &err))),
+    }).responder()
+}
+
+/// Handle `req`, holding `permit` until the handler is done with it.
+fn respond<M, A>(
+    req: HttpRequest<Addr<A>>,
+    config: &ExposeConfig,
+    permit: Option<crate::Permit>,
+) -> FutureResponse<HttpResponse, Error>
+	where
+        A: actix::Actor<Context=actix::Context<A>> + actix::Handler<M>,
+	    M: 'static + MessageExt
 {
     trace!("Recieved request: {:?}", &req);
     let addr = req.state().clone();
-    req.body().map_err(Error::from)
-    	.and_then(|body| {
-            trace!("Received message: {:?}. Deserialize as {:?}", body, crate::get_type!(M));
-    		deserialize(&body).map_err(|err| {
-                error!("Failed to deserialize request: {}", err);
-                Error::from(err)
+    let encoding = Encoding::of(&req);
+    let (compression, limit, head) = (config.compression.clone(), config.limit, req.clone());
+    let dedupe = config.dedupe.clone().and_then(|dedupe| idempotency_key(&req).map(|key| (dedupe, key)));
+    if prefers_async(&req) {
+        return read_request::<M, A>(req, limit)
+            .then(move |res| -> Result<HttpResponse, Error> {
+                match res {
+                    Ok(msg) => {
+                        trace!("Forwarding message to local handler, without waiting for it");
+                        actix::Arbiter::spawn(addr.send(msg).then(move |_| {
+                            drop(permit);
+                            Ok(())
+                        }));
+                        Ok(HttpResponse::Accepted().finish())
+                    },
+                    Err(err) => Ok(error_response(encoding, &err)),
+                }
             })
-    	})
+            .responder();
+    }
+    read_request::<M, A>(req, limit)
         .and_then(move |req: M| {
             trace!("Forwarding message to local handler");
-            addr.send(req).map_err(|err| {
+            let send = move || addr.send(req).map_err(|err| {
                 error!("Failed to send to local handler: {}", err);
                 Error::from(err)
-            })
+            });
+            match dedupe {
+                Some((dedupe, key)) => future::Either::A(dedupe.handle::<M, _, _>(key, send)),
+                None => future::Either::B(send()),
+            }
         })
-        .and_then(|resp| future::result(serialize(&resp)).map_err(Error::from))
-        .map(|resp| {
-            trace!("Handled request successfully");
-            HttpResponse::Ok().body(resp)
+        .and_then(move |resp| attachment::serialize(encoding, &resp))
+        .and_then(move |body| match body {
+            Body::Full(body) => match compression.for_response(&head, &body) {
+                Some(coding) => Ok((Body::Full(coding.compress(&body)?), Some(coding))),
+                None => Ok((Body::Full(body), None)),
+            },
+            framed => Ok((framed, None)),
+        })
+        .then(move |res| -> Result<HttpResponse, Error> {
+            drop(permit);
+            match res {
+                Ok((body, coding)) => {
+                    trace!("Handled request successfully");
+                    let mut resp = HttpResponse::Ok();
+                    if let Some(coding) = coding {
+                        resp.header(header::CONTENT_ENCODING, coding.as_str());
+                    }
+                    resp.content_type(encoding.content_type())
+                        .content_encoding(ContentEncoding::Identity);
+                    Ok(match body {
+                        Body::Full(body) => resp.body(body),
+                        Body::Framed { attachments, stream } => {
+                            resp.header(attachment::HEADER, attachments.to_string()).streaming(stream)
+                        },
+                    })
+                },
+                Err(err) => Ok(error_response(encoding, &err)),
+            }
         })
         .responder()
 }
+
+fn prefers_async<S>(req: &HttpRequest<S>) -> bool {
+    req.headers().get_all(PREFER).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|pref| pref.trim().eq_ignore_ascii_case(RESPOND_ASYNC))
+}
+
+fn idempotency_key<S>(req: &HttpRequest<S>) -> Option<String> {
+    req.headers().get(super::IDEMPOTENCY_KEY)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Decode the message of `req`, reading at most `limit` bytes of it.
+fn read_request<M, A>(req: HttpRequest<Addr<A>>, limit: usize) -> impl Future<Item=M, Error=Error>
+    where
+        A: actix::Actor,
+        M: 'static + MessageExt
+{
+    let (encoding, head) = (Encoding::of(&req), req.clone());
+    let msg = match attachment::count(&req) {
+        Some(count) => future::Either::A(attachment::deserialize(encoding, req.payload(), count, limit)),
+        None => future::Either::B(read_body(req, limit)
+            .and_then(move |body| {
+                trace!("Received message: {:?}. Deserialize as {:?}", body, crate::get_type!(M));
+                encoding.deserialize(&body).map_err(|err| {
+                    error!("Failed to deserialize request: {}", err);
+                    err
+                })
+            })),
+    };
+    msg.map(move |mut msg| {
+        set_peer(&head, &mut msg);
+        msg
+    })
+}
+
+/// Hand `msg` the credentials of the local process which sent `req`, if known.
+pub(crate) fn set_peer<M: MessageExt, S>(req: &HttpRequest<S>, msg: &mut M) {
+    #[cfg(unix)]
+    {
+        if let Some(peer) = req.extensions().get::<super::PeerCred>() {
+            msg.set_peer(*peer);
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (req, msg);
+    }
+}
+
+/// The decompressed body of `req`, up to `limit` bytes.
+pub(crate) fn read_body<S: 'static>(req: HttpRequest<S>, limit: usize) -> impl Future<Item=Vec<u8>, Error=Error> {
+    let coding = Coding::of(&req);
+    req.body().limit(limit).map_err(move |err| super::payload_error(err, limit))
+        .and_then(move |body| match coding? {
+            // actix-web already decompresses the others
+            #[cfg(feature = "zstd")]
+            Some(Coding::Zstd) => Coding::Zstd.decompress_limited(&body, limit),
+            _ => Ok(body.to_vec()),
+        })
+}
+
+/// The status of a response failing with `err`.
+pub(crate) fn status_for(err: &Error) -> StatusCode {
+    if err.downcast_ref::<PayloadTooLarge>().is_some() || err.downcast_ref::<super::TooManyEntries>().is_some() {
+        StatusCode::PAYLOAD_TOO_LARGE
+    } else if err.downcast_ref::<super::TooManyAttachments>().is_some() {
+        StatusCode::BAD_REQUEST
+    } else if err.downcast_ref::<crate::Overloaded>().is_some() {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else if err.downcast_ref::<super::InProgress>().is_some() {
+        StatusCode::CONFLICT
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+/// Report `err` to the caller as an `ErrorEnvelope`.
+pub(crate) fn error_response(encoding: Encoding, err: &Error) -> HttpResponse {
+    let envelope = ErrorEnvelope { error: err.to_string() };
+    let mut resp = HttpResponse::build(status_for(err));
+    match encoding.serialize(&envelope) {
+        Ok(body) => resp.content_type(encoding.content_type()).body(body),
+        Err(_) => resp.body(envelope.error),
+    }
+}
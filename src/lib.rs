@@ -18,18 +18,34 @@
 //!
 //! Future ideas include adding service discovery/proxies as a supported endpoint.
 
+#[cfg(unix)]
+mod activation;
 pub mod app;
+mod error;
+mod event;
+mod limit;
+mod outbox;
 #[cfg(unix)]
 pub mod plugin;
 pub mod http;
 mod router;
 // pub mod rpc;
 pub mod service;
+#[cfg(unix)]
+pub mod socket;
+mod stream;
 
 pub use self::app::{App, Routeable, RouteType};
+pub use self::error::DirectoryError;
+pub use self::event::{Delivery, EventExt, PublishReport};
 #[cfg(unix)]
 pub use self::plugin::Plugin;
-pub use self::router::PendingRoute;
+pub use self::limit::{Limiter, Overloaded, Permit};
+pub use self::outbox::{DeadLetter, Outbox};
+pub use self::router::{CachingClient, Durable, Invalidate, IntoMember, Limited, Member, PendingRoute, Reply, Scatter, ScatterError, SingleFlight};
+#[cfg(unix)]
+pub use self::socket::SocketDir;
+pub use self::stream::{MessageStream, StreamingMessageExt};
 
 #[cfg(test)]
 pub mod test_helpers;
@@ -40,7 +56,7 @@ use futures::Future;
 use serde::{Deserialize, de::DeserializeOwned, Serialize};
 
 pub mod prelude {
-	pub use crate::{app, http::HttpApp, router::Remote, service::Service, App, FutActResponse, FutResponse, MessageExt, Routeable, RouteType, PendingRoute, OpaqueMessage,};
+	pub use crate::{app, http::HttpApp, router::{Remote, Upstream}, service::Service, App, EventExt, FutActResponse, FutResponse, MessageExt, MessageStream, Routeable, RouteType, PendingRoute, OpaqueMessage, SingleFlight, CachingClient, Limited, Limiter, Durable, Outbox, StreamingMessageExt,};
 	#[cfg(unix)]
 	pub use crate::Plugin;
 }
@@ -56,6 +72,20 @@ pub trait MessageExt:
 	const PATH: &'static str;
 
     type Response: 'static + Send + DeserializeOwned + Serialize;
+
+    /// Identifies messages which get the same response, so a `SingleFlight`
+    /// route sends only one of them at a time. Defaults to the serialized message.
+    /// `None` opts the message out.
+    fn key(&self) -> Option<Vec<u8>> {
+        serialize(self).ok()
+    }
+
+    /// Given the credentials of the local process which sent the message, when
+    /// it arrives through `serve_local_http`, before any handler sees it.
+    /// Messages which audit their senders keep it in a `#[serde(skip)]` field,
+    /// so remotes can't claim to be someone else. Ignored by default.
+    #[cfg(unix)]
+    fn set_peer(&mut self, _peer: crate::http::PeerCred) {}
 }
 
 /// Wrapper type for a response to a `MessageExt`. 
@@ -91,7 +121,48 @@ impl<A, M> MessageResponse<A, M> for FutResponse<M>
                 tx.send(res)
             }
             Ok(())
-        }).map_err(|_| ()));
+        }).map_err(|err| log::error!("Failed to handle message: {}", err)));
+    }
+}
+
+/// Response of the actors which pass messages on to another handler.
+///
+/// Like `FutResponse` when the sender waits for the reply. Messages sent with
+/// `do_send` are passed on without expecting one, so remotes don't send it back.
+pub struct ForwardResponse<M: MessageExt> {
+    msg: M,
+    send: Forward<M, M::Response>,
+    notify: Forward<M, ()>,
+}
+
+type Forward<M, T> = Box<dyn FnOnce(M) -> Box<dyn Future<Item=T, Error=Error>>>;
+
+impl<M: MessageExt> ForwardResponse<M> {
+    pub(crate) fn new<S, SF, N, NF>(msg: M, send: S, notify: N) -> Self
+        where S: 'static + FnOnce(M) -> SF,
+              SF: 'static + Future<Item=M::Response, Error=Error>,
+              N: 'static + FnOnce(M) -> NF,
+              NF: 'static + Future<Item=(), Error=Error>,
+    {
+        ForwardResponse {
+            msg,
+            send: Box::new(move |msg| Box::new(send(msg))),
+            notify: Box::new(move |msg| Box::new(notify(msg))),
+        }
+    }
+}
+
+impl<A, M> MessageResponse<A, M> for ForwardResponse<M>
+    where
+        A: Actor<Context=Context<A>>,
+        M: MessageExt,
+{
+    fn handle<R: ResponseChannel<M>>(self, ctxt: &mut Context<A>, tx: Option<R>) {
+        let ForwardResponse { msg, send, notify } = self;
+        match tx {
+            Some(tx) => MessageResponse::<A, M>::handle(FutResponse::<M>(send(msg)), ctxt, Some(tx)),
+            None => Arbiter::spawn(notify(msg).map_err(|err| log::error!("Failed to forward message: {}", err))),
+        }
     }
 }
 
@@ -118,7 +189,7 @@ impl<A, M> MessageResponse<A, M> for FutActResponse<A, M>
     	    }
     	    actix::fut::ok(())
     	    // Ok(()).into_future().into_actor(act)
-    	}).map_err(|_, _, _| ()));
+    	}).map_err(|err, _, _| log::error!("Failed to handle message: {}", err)));
     }
 }
 
@@ -368,7 +439,7 @@ mod tests {
 	        let addr = TestHandler::default();
 	        let app = app::App::new()
 	            .service(addr);
-	       	let socket_addr = app.serve_local_http(None);
+	       	let socket_addr = app.serve_local_http(None).unwrap().addr().clone();
 	        app.make_current();
 	        sender.send(socket_addr).unwrap();
 	        sys.run();
@@ -388,23 +459,1200 @@ mod tests {
 	    assert_eq!(res.0, 69);
 	}
 
+	#[test]
+	fn test_server_handle() {
+	    init_logger();
+	    let mut sys = System::new("test_server");
+	    let app = app::App::new()
+	        .service(TestHandler::default())
+	        .expose::<TestMessage>();
+	    let tcp = app.serve_http("127.0.0.1:0").unwrap();
+	    #[cfg(unix)]
+	    let unix = app.serve_local_http(None).unwrap();
+	    app.make_current();
+
+	    let url = Url::parse(&tcp.addr().to_string()).unwrap();
+	    let res = sys.block_on(crate::http::send(&TestMessage(3), url.clone()));
+	    assert_eq!(res.unwrap(), TestResponse(3));
+	    sys.block_on(tcp.stop()).unwrap();
+	    sys.block_on(tcp.join()).unwrap();
+	    assert!(sys.block_on(crate::http::send(&TestMessage(3), url)).is_err());
+
+	    #[cfg(unix)]
+	    {
+	        let path = app::APP.with(|app| app.borrow().sock_path("main").unwrap());
+	        let res = sys.block_on(crate::http::send_local(&TestMessage(4), &path));
+	        assert_eq!(res.unwrap(), TestResponse(4));
+	        sys.block_on(unix.stop()).unwrap();
+	        sys.block_on(unix.join()).unwrap();
+	        assert!(!path.exists());
+	    }
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_peer_policy() {
+	    use crate::http::{PeerCred, PeerPolicy, RemoteError};
+	    init_logger();
+	    let mut sys = System::new("test_server");
+	    let uid = unsafe { libc::getuid() };
+	    let seen = std::sync::Arc::new(std::sync::Mutex::new(None::<PeerCred>));
+	    let seen2 = seen.clone();
+	    let app = app::App::new()
+	        .service(TestHandler::default())
+	        .peer_policy(PeerPolicy::callback(move |peer| {
+	            *seen2.lock().unwrap() = Some(*peer);
+	            peer.uid == uid
+	        }));
+	    let path = app.sock_path("main").unwrap();
+	    let _server = app.serve_local_http(None).unwrap();
+	    app.make_current();
+
+	    let res = sys.block_on(crate::http::send_local(&TestMessage(1), &path));
+	    assert_eq!(res.unwrap(), TestResponse(1));
+	    let peer = seen.lock().unwrap().unwrap();
+	    assert_eq!(peer.uid, uid);
+	    #[cfg(target_os = "linux")]
+	    assert_eq!(peer.pid, Some(std::process::id()));
+
+	    // Message handlers are given the peer by `MessageExt::set_peer`, even in batches
+	    let res = sys.block_on(crate::http::send_local(&TestWhoAmI::default(), &path));
+	    assert_eq!(res.unwrap(), Some(uid));
+	    let batch = crate::http::BatchConfig { window: time::Duration::from_millis(20), max_size: 2 };
+	    let upstream = Remote::LocalHttp(path.clone()).with_config(crate::http::ClientConfig { batch: Some(batch), ..Default::default() });
+	    let sends: Vec<_> = (0..2).map(|_| upstream.send(&TestWhoAmI::default()).0).collect();
+	    assert_eq!(sys.block_on(futures::future::join_all(sends)).unwrap(), vec![Some(uid), Some(uid)]);
+
+	    let app = app::App::new()
+	        .service(TestHandler::default())
+	        .peer_policy(PeerPolicy::Allow { uids: vec![uid.wrapping_add(1)], gids: Vec::new() });
+	    let path = app.sock_path("main").unwrap();
+	    let _server = app.serve_local_http(None).unwrap();
+	    app.make_current();
+
+	    let err = sys.block_on(crate::http::send_local(&TestMessage(1), &path)).unwrap_err();
+	    assert_eq!(err.downcast_ref::<RemoteError>().unwrap().status, 403);
+	}
+
+	#[test]
+	fn test_connection_pool() {
+	    use crate::http::{ClientConfig, PoolConfig};
+	    init_logger();
+	    let mut sys = System::new("test_client");
+	    let (sender, receiver) = mpsc::sync_channel(1);
+	    thread::spawn(move || {
+	        let sys = System::new("test_server");
+	        let app = app::App::new()
+	            .service(TestHandler::default())
+	            .expose::<TestMessage>();
+	        let tcp = app.serve_http("127.0.0.1:0").unwrap();
+	        #[cfg(unix)]
+	        let unix = app.serve_local_http(None).unwrap();
+	        app.make_current();
+	        sender.send(vec![tcp.addr().clone(), #[cfg(unix)] unix.addr().clone()]).unwrap();
+	        sys.run();
+	    });
+
+	    let config = ClientConfig { pool: PoolConfig { size: 1, ..Default::default() }, ..Default::default() };
+	    for addr in receiver.recv().unwrap() {
+	        let upstream = Remote::from(addr).with_config(config.clone());
+	        app::App::new()
+	            .route::<TestMessage, _>(upstream.clone(), RouteType::Upstream)
+	            .make_current();
+	        for i in 0..3 {
+	            assert_eq!(sys.block_on(app::send(TestMessage(i))).unwrap(), TestResponse(i));
+	        }
+	        // Requests wait for the only connection
+	        let all = futures::future::join_all((0..3).map(|i| app::send(TestMessage(i))));
+	        assert_eq!(sys.block_on(all).unwrap().len(), 3);
+	        // Stats of HTTP connections are only reported every second
+	        let wait = tokio::timer::Delay::new(time::Instant::now() + time::Duration::from_millis(1500));
+	        sys.block_on(wait).unwrap();
+	        let stats = upstream.pool_stats();
+	        assert_eq!(stats.opened, 1, "{:?}", stats);
+	        assert_eq!(stats.reused, 5, "{:?}", stats);
+	    }
+	}
+
+	#[test]
+	fn test_compression() {
+	    use actix_web::{client::ClientRequest, HttpMessage};
+	    use crate::http::{ClientConfig, Coding, Compression, ExposeConfig};
+	    init_logger();
+	    let mut sys = System::new("test_client");
+	    let codings = vec![#[cfg(feature = "zstd")] Coding::Zstd, Coding::Deflate];
+	    let compression = Compression { codings: codings.clone(), threshold: 0 };
+	    let (sender, receiver) = mpsc::sync_channel(1);
+	    thread::spawn(move || {
+	        let sys = System::new("test_server");
+	        let app = app::App::new()
+	            .service(TestHandler::default())
+	            .expose_with::<TestMessage>(ExposeConfig { compression, ..Default::default() });
+	        let tcp = app.serve_http("127.0.0.1:0").unwrap();
+	        #[cfg(unix)]
+	        let unix = app.serve_local_http(None).unwrap();
+	        app.make_current();
+	        sender.send(vec![tcp.addr().clone(), #[cfg(unix)] unix.addr().clone()]).unwrap();
+	        sys.run();
+	    });
+	    let addrs = receiver.recv().unwrap();
+
+	    for addr in addrs.iter() {
+	        for &coding in [#[cfg(feature = "zstd")] Coding::Zstd, Coding::Gzip, Coding::Deflate].iter() {
+	            let config = ClientConfig { compression: Compression::new(coding, 0), ..Default::default() };
+	            app::App::new()
+	                .route::<TestMessage, _>(Remote::from(addr.clone()).with_config(config), RouteType::Upstream)
+	                .make_current();
+	            assert_eq!(sys.block_on(app::send(TestMessage(5))).unwrap(), TestResponse(5));
+	        }
+	    }
+
+	    // Responses are only compressed for clients accepting the coding
+	    let url = Url::parse(&addrs[0].to_string()).unwrap().join(TestMessage::PATH).unwrap();
+	    let post = |accept: &str| {
+	        let body = crate::serialize(TestMessage(5)).unwrap();
+	        ClientRequest::post(url.clone())
+	            .header("Accept-Encoding", accept)
+	            .disable_decompress()
+	            .body(body)
+	            .unwrap()
+	            .send()
+	    };
+	    let resp = sys.block_on(post("gzip, zstd, deflate")).unwrap();
+	    assert_eq!(resp.headers()["content-encoding"], codings[0].as_str());
+	    let body = sys.block_on(resp.body()).unwrap();
+	    let resp: TestResponse = crate::deserialize(&codings[0].decompress(&body).unwrap()).unwrap();
+	    assert_eq!(resp, TestResponse(5));
+	    let resp = sys.block_on(post("gzip")).unwrap();
+	    assert!(resp.headers().get("content-encoding").is_none());
+	}
+
+	#[test]
+	fn test_payload_limits() {
+	    use crate::http::{Attachment, ClientConfig, ExposeConfig, PayloadTooLarge, RemoteError};
+	    init_logger();
+	    let mut sys = System::new("test_client");
+	    let (sender, receiver) = mpsc::sync_channel(1);
+	    thread::spawn(move || {
+	        let sys = System::new("test_server");
+	        let handler = TestHandler::default().start();
+	        let app = app::App::new()
+	            .route::<TestMessage, _>(handler.clone(), RouteType::Server)
+	            .route::<TestMessageEmpty, _>(handler.clone(), RouteType::Server)
+	            .route::<TestUpload, _>(handler, RouteType::Server)
+	            .expose_with::<TestMessage>(ExposeConfig { limit: 0, ..Default::default() })
+	            .expose::<TestMessageEmpty>()
+	            .expose::<TestUpload>();
+	        let tcp = app.serve_http("127.0.0.1:0").unwrap();
+	        #[cfg(unix)]
+	        let unix = app.serve_local_http(None).unwrap();
+	        app.make_current();
+	        sender.send(vec![tcp.addr().clone(), #[cfg(unix)] unix.addr().clone()]).unwrap();
+	        sys.run();
+	    });
+	    let addrs = receiver.recv().unwrap();
+
+	    let data: Vec<u8> = (0..3 * crate::http::DEFAULT_LIMIT).map(|i| i as u8).collect();
+	    // Errors are only seen by calling the remote directly. Only the exposed
+	    // server is configured per message, the local one has the default limit.
+	    let FutResponse(fut) = Upstream::from(addrs[0].clone()).send(&TestMessage(5));
+	    let err = sys.block_on(fut).unwrap_err();
+	    assert_eq!(err.downcast_ref::<RemoteError>().unwrap().status, 413);
+
+	    for addr in addrs.iter() {
+	        let upstream = |config: ClientConfig| Remote::from(addr.clone()).with_config(config);
+	        // Attachments don't count towards the limits
+	        let FutResponse(fut) = upstream(ClientConfig::default()).send(&TestUpload(Attachment::from_bytes(data.clone())));
+	        let TestUpload(echo) = sys.block_on(fut).unwrap();
+	        assert_eq!(sys.block_on(echo.concat()).unwrap(), data);
+
+	        let FutResponse(fut) = upstream(ClientConfig { limit: 0, ..Default::default() }).send(&TestMessageEmpty);
+	        let err = sys.block_on(fut).unwrap_err();
+	        assert_eq!(err.downcast_ref::<PayloadTooLarge>().unwrap().limit, 0);
+	    }
+
+	    // Bodies claiming more attachments than could be accepted are refused
+	    let url = Url::parse(&addrs[0].to_string()).unwrap().join(TestUpload::PATH).unwrap();
+	    for count in &["1025", "18446744073709551615"] {
+	        let req = actix_web::client::ClientRequest::post(url.clone()).header("X-Attachments", *count).body(Vec::new()).unwrap();
+	        let resp = sys.block_on(req.send()).unwrap();
+	        assert_eq!(resp.status().as_u16(), 400);
+	    }
+	}
+
+	#[test]
+	fn test_streaming() {
+	    use futures::Stream;
+	    use std::sync::atomic::Ordering;
+	    init_logger();
+	    let mut sys = System::new("test_client");
+	    let (sender, receiver) = mpsc::sync_channel(1);
+	    thread::spawn(move || {
+	        let sys = System::new("test_server");
+	        let app = app::App::new()
+	            .route::<TestCount, _>(TestHandler::default().start(), RouteType::Server)
+	            .expose::<TestCount>();
+	        let tcp = app.serve_http("127.0.0.1:0").unwrap();
+	        #[cfg(unix)]
+	        let unix = app.serve_local_http(None).unwrap();
+	        app.make_current();
+	        sender.send(vec![tcp.addr().clone(), #[cfg(unix)] unix.addr().clone()]).unwrap();
+	        sys.run();
+	    });
+	    let addrs = receiver.recv().unwrap();
+
+	    // Locally, the stream is handed over as it is
+	    app::App::new()
+	        .route::<TestCount, _>(TestHandler::default().start(), RouteType::Server)
+	        .make_current();
+	    assert_eq!(sys.block_on(app::send_stream(TestCount(Some(3))).collect()).unwrap(), vec![0, 1, 2]);
+
+	    for addr in addrs.iter() {
+	        app::App::new()
+	            .route::<TestCount, _>(Remote::from(addr.clone()), RouteType::Upstream)
+	            .make_current();
+	        let items = sys.block_on(app::send_stream(TestCount(Some(100_000))).collect()).unwrap();
+	        assert_eq!(items, (0..100_000).collect::<Vec<_>>());
+
+	        // Dropping an endless stream stops the handler's
+	        let items = sys.block_on(app::send_stream(TestCount(None)).take(3).collect()).unwrap();
+	        assert_eq!(items, vec![0, 1, 2]);
+	        let start = time::Instant::now();
+	        while TEST_COUNTS.load(Ordering::SeqCst) > 0 {
+	            assert!(start.elapsed() < time::Duration::from_secs(5), "stream to {} wasn't cancelled", addr);
+	            // Keep the client running, it closes the connection
+	            let _ = sys.block_on(tokio::timer::Delay::new(time::Instant::now() + time::Duration::from_millis(10)));
+	        }
+	    }
+	}
+
+	#[test]
+	fn test_publish() {
+	    use std::sync::atomic::Ordering;
+	    init_logger();
+	    let mut sys = System::new("test_client");
+	    let (sender, receiver) = mpsc::sync_channel(1);
+	    thread::spawn(move || {
+	        let sys = System::new("test_server");
+	        let app = app::App::new()
+	            .route::<TestEvent, _>(TestHandler::default().start(), RouteType::Server)
+	            .expose::<TestEvent>();
+	        let tcp = app.serve_http("127.0.0.1:0").unwrap();
+	        app.make_current();
+	        sender.send(tcp.addr().clone()).unwrap();
+	        sys.run();
+	    });
+	    let addr = receiver.recv().unwrap();
+
+	    let dead = Url::parse("http://127.0.0.1:1/").unwrap();
+	    app::App::new()
+	        .subscribe::<TestEvent, _>(TestHandler::default().start())
+	        .subscribe::<TestEvent, _>(Remote::from(addr))
+	        .subscribe::<TestEvent, _>(dead)
+	        .make_current();
+	    let report = sys.block_on(app::publish(TestEvent(1))).unwrap();
+	    assert_eq!(report.deliveries.len(), 3);
+	    assert!(!report.is_ok());
+	    let failures: Vec<_> = report.failures().map(|delivery| delivery.subscriber.as_str()).collect();
+	    assert_eq!(failures.len(), 1);
+	    assert!(failures[0].contains("127.0.0.1:1"), "{}", failures[0]);
+	    assert_eq!(TEST_EVENTS.load(Ordering::SeqCst), 2);
+
+	    // Without subscribers, there's nobody to fail
+	    app::App::new().make_current();
+	    assert!(sys.block_on(app::publish(TestEvent(1))).unwrap().deliveries.is_empty());
+	}
+
+	#[test]
+	fn test_notify() {
+	    use actix_web::client::ClientRequest;
+	    use std::sync::atomic::Ordering;
+	    init_logger();
+	    let mut sys = System::new("test_client");
+	    let (sender, receiver) = mpsc::sync_channel(1);
+	    thread::spawn(move || {
+	        let sys = System::new("test_server");
+	        let app = app::App::new()
+	            .route::<TestNote, _>(TestHandler::default().start(), RouteType::Server)
+	            .expose::<TestNote>();
+	        let tcp = app.serve_http("127.0.0.1:0").unwrap();
+	        #[cfg(unix)]
+	        let unix = app.serve_local_http(None).unwrap();
+	        app.make_current();
+	        sender.send(vec![tcp.addr().clone(), #[cfg(unix)] unix.addr().clone()]).unwrap();
+	        sys.run();
+	    });
+	    let addrs = receiver.recv().unwrap();
+	    let wait_for = |sys: &mut actix::SystemRunner, count: usize| {
+	        let start = time::Instant::now();
+	        while TEST_NOTES.load(Ordering::SeqCst) < count {
+	            assert!(start.elapsed() < time::Duration::from_secs(5), "note {} wasn't handled", count);
+	            let _ = sys.block_on(tokio::timer::Delay::new(time::Instant::now() + time::Duration::from_millis(10)));
+	        }
+	    };
+
+	    app::App::new()
+	        .route::<TestNote, _>(TestHandler::default().start(), RouteType::Client)
+	        .make_current();
+	    app::notify(TestNote(1)).unwrap();
+	    wait_for(&mut sys, 1);
+
+	    for (i, addr) in addrs.iter().enumerate() {
+	        app::App::new()
+	            .route::<TestNote, _>(Remote::from(addr.clone()), RouteType::Upstream)
+	            .make_current();
+	        app::notify(TestNote(1)).unwrap();
+	        wait_for(&mut sys, 2 + i);
+	    }
+
+	    // The server accepts the message before handling it, and doesn't reply
+	    let url = Url::parse(&addrs[0].to_string()).unwrap().join(TestNote::PATH).unwrap();
+	    let body = crate::serialize(TestNote(1)).unwrap();
+	    let req = ClientRequest::post(url).header("Prefer", "respond-async").body(body).unwrap();
+	    let resp = sys.block_on(req.send()).unwrap();
+	    assert_eq!(resp.status().as_u16(), 202);
+	    wait_for(&mut sys, 1 + addrs.len() + 1);
+
+	    // Without a route, there's nowhere to deliver it
+	    app::App::new().make_current();
+	    assert!(app::notify(TestNote(1)).is_err());
+	}
+
+	#[test]
+	fn test_batching() {
+	    use crate::http::{BatchConfig, ClientConfig, RemoteError};
+	    use futures::future;
+	    init_logger();
+	    let mut sys = System::new("test_client");
+	    let (sender, receiver) = mpsc::sync_channel(1);
+	    thread::spawn(move || {
+	        let sys = System::new("test_server");
+	        let app = app::App::new().service(TestHandler::default());
+	        let tcp = app.serve_http("127.0.0.1:0").unwrap();
+	        #[cfg(unix)]
+	        let unix = app.serve_local_http(None).unwrap();
+	        app.make_current();
+	        sender.send(vec![tcp.addr().clone(), #[cfg(unix)] unix.addr().clone()]).unwrap();
+	        sys.run();
+	    });
+	    let addrs = receiver.recv().unwrap();
+
+	    for addr in addrs.iter() {
+	        let batch = BatchConfig { window: time::Duration::from_millis(20), max_size: 3 };
+	        let upstream = Remote::from(addr.clone()).with_config(ClientConfig { batch: Some(batch), ..Default::default() });
+	        // Sent as soon as the batch is full
+	        let sends: Vec<_> = (0..3).map(|i| upstream.send(&TestMessage(i)).0).collect();
+	        let replies = sys.block_on(future::join_all(sends)).unwrap();
+	        assert_eq!(replies, vec![TestResponse(0), TestResponse(1), TestResponse(2)]);
+
+	        // Or once the window has passed, with an outcome for each message
+	        let ok = upstream.send(&TestMessage(4)).0;
+	        let FutResponse(missing) = upstream.send(&TestNote(1));
+	        let (ok, missing) = sys.block_on(ok.then(Ok::<_, ()>).join(missing.then(Ok))).unwrap();
+	        assert_eq!(ok.unwrap(), TestResponse(4));
+	        assert_eq!(missing.unwrap_err().downcast_ref::<RemoteError>().unwrap().status, 404);
+
+	        #[cfg(unix)]
+	        {
+	            if let crate::http::ListenAddr::Unix(_) = addr {
+	                let stats = upstream.pool_stats();
+	                assert_eq!(stats.opened + stats.reused, 2, "{:?}", stats);
+	            }
+	        }
+	    }
+
+	    // Batches of more messages than the server accepts are refused whole
+	    let batch = BatchConfig { window: time::Duration::from_millis(20), max_size: 65 };
+	    let upstream = Remote::from(addrs[0].clone()).with_config(ClientConfig { batch: Some(batch), ..Default::default() });
+	    let sends: Vec<_> = (0..65).map(|_| upstream.send(&TestMessageEmpty).0.then(Ok::<_, ()>)).collect();
+	    for res in sys.block_on(future::join_all(sends)).unwrap() {
+	        let err = res.unwrap_err();
+	        let err = err.downcast_ref::<RemoteError>().unwrap();
+	        assert_eq!((err.status, &*err.message), (413, "batch has 65 entries, but at most 64 are accepted"));
+	    }
+	}
+
+	#[test]
+	fn test_single_flight() {
+	    use futures::future;
+	    use std::sync::atomic::Ordering;
+	    init_logger();
+	    let mut sys = System::new("test_client");
+	    app::App::new()
+	        .route::<TestSlow, _>(SingleFlight::new(TestHandler::default().start()), RouteType::Client)
+	        .make_current();
+	    let sends: Vec<_> = [101, 101, 102, 101, 102].iter().map(|&i| app::send(TestSlow(i))).collect();
+	    assert_eq!(sys.block_on(future::join_all(sends)).unwrap(), vec![101, 101, 102, 101, 102]);
+	    assert_eq!(TEST_SLOWS[101].load(Ordering::SeqCst), 1);
+	    assert_eq!(TEST_SLOWS[102].load(Ordering::SeqCst), 1);
+
+	    // Responses aren't kept once the request is done
+	    assert_eq!(sys.block_on(app::send(TestSlow(101))).unwrap(), 101);
+	    assert_eq!(TEST_SLOWS[101].load(Ordering::SeqCst), 2);
+	}
+
+	#[test]
+	fn test_caching_client() {
+	    use crate::Invalidate;
+	    use std::sync::atomic::Ordering;
+	    init_logger();
+	    let mut sys = System::new("test_client");
+	    let ttl = time::Duration::from_millis(100);
+	    let cache = CachingClient::<TestLookup>::new(TestHandler::default().start())
+	        .ttl(ttl)
+	        .negative_ttl(ttl)
+	        .max_entries(2)
+	        .start();
+	    app::App::new()
+	        .route::<TestLookup, _>(cache.clone(), RouteType::Client)
+	        .make_current();
+	    let mut lookup = |n: u8, handled: usize| {
+	        let res = sys.block_on(app::send(TestLookup(n)));
+	        assert_eq!(TEST_LOOKUPS.load(Ordering::SeqCst), handled, "looking up {}", n);
+	        res
+	    };
+
+	    assert_eq!(lookup(1, 1).unwrap(), 1);
+	    assert_eq!(lookup(1, 1).unwrap(), 1);
+	    lookup(2, 2).unwrap();
+	    // The least recently used entry, 2, is evicted
+	    lookup(1, 2).unwrap();
+	    lookup(3, 3).unwrap();
+	    lookup(1, 3).unwrap();
+	    lookup(2, 4).unwrap();
+
+	    // Failures are kept too
+	    assert!(lookup(0, 5).is_err());
+	    assert!(lookup(0, 5).is_err());
+
+	    // Handled before the next messages, which go through the same mailbox
+	    cache.do_send(Invalidate::message(TestLookup(0)));
+	    assert!(lookup(0, 6).is_err());
+	    cache.do_send(Invalidate::all());
+	    lookup(0, 7).unwrap_err();
+	    lookup(0, 7).unwrap_err();
+
+	    // And expire
+	    thread::sleep(ttl);
+	    lookup(0, 8).unwrap_err();
+
+	    // Responses to messages sent before invalidating aren't kept
+	    let slow = CachingClient::<TestSlow>::new(TestHandler::default().start()).start();
+	    app::App::new()
+	        .route::<TestSlow, _>(slow.clone(), RouteType::Client)
+	        .make_current();
+	    let invalidate = tokio::timer::Delay::new(time::Instant::now() + time::Duration::from_millis(10))
+	        .map(move |()| slow.do_send(Invalidate::all()));
+	    let (first, ()) = sys.block_on(app::send(TestSlow(111)).join(invalidate.map_err(failure::Error::from))).unwrap();
+	    assert_eq!(first, 111);
+	    assert_eq!(sys.block_on(app::send(TestSlow(111))).unwrap(), 111);
+	    assert_eq!(TEST_SLOWS[111].load(Ordering::SeqCst), 2);
+	}
+
+	#[test]
+	fn test_limits() {
+	    use crate::http::{BatchConfig, ClientConfig, ExposeConfig, RemoteError};
+	    use crate::Overloaded;
+	    use actix::Handler;
+	    use futures::{future, Future};
+	    init_logger();
+	    let mut sys = System::new("test_client");
+
+	    // Beyond the limits, requests wait in the queue, then are turned away
+	    let limiter = Limiter::new().max_concurrent(1).queue(1);
+	    let permit = sys.block_on(limiter.acquire()).unwrap();
+	    let mut queued = limiter.acquire();
+	    let rejected = sys.block_on(future::lazy(|| {
+	        assert!(queued.poll().unwrap().is_not_ready());
+	        limiter.acquire().poll()
+	    })).unwrap_err();
+	    assert_eq!(rejected.downcast_ref::<Overloaded>().unwrap().waiting, 1);
+	    drop(permit);
+	    let permit = sys.block_on(queued).unwrap();
+	    assert_eq!(limiter.in_flight(), 1);
+	    drop(permit);
+	    assert_eq!(limiter.in_flight(), 0);
+
+	    // Requests are spread out once the burst is spent
+	    let limiter = Limiter::new().rate(20.0, 1).queue(10);
+	    let start = time::Instant::now();
+	    for _ in 0..3 {
+	        sys.block_on(limiter.acquire()).unwrap();
+	    }
+	    assert!(start.elapsed() >= time::Duration::from_millis(90), "{:?}", start.elapsed());
+
+	    let (sender, receiver) = mpsc::sync_channel(1);
+	    thread::spawn(move || {
+	        let sys = System::new("test_server");
+	        let app = app::App::new()
+	            .route::<TestSlow, _>(TestHandler::default().start(), RouteType::Server)
+	            .expose_with::<TestSlow>(ExposeConfig { limiter: Some(Limiter::new().max_concurrent(1)), ..Default::default() });
+	        let tcp = app.serve_http("127.0.0.1:0").unwrap();
+	        app.make_current();
+	        sender.send(tcp.addr().clone()).unwrap();
+	        sys.run();
+	    });
+	    let addr = receiver.recv().unwrap();
+
+	    // The server turns away requests beyond its own limits
+	    let upstream = Upstream::from(addr.clone());
+	    let sends: Vec<_> = (0..2).map(|i| upstream.send(&TestSlow(i)).0.then(Ok::<_, ()>)).collect();
+	    let results = sys.block_on(future::join_all(sends)).unwrap();
+	    assert_eq!(results.iter().filter(|res| res.is_ok()).count(), 1);
+	    let err = results.into_iter().find_map(Result::err).unwrap();
+	    assert_eq!(err.downcast_ref::<RemoteError>().unwrap().status, 503);
+
+	    // Batches are held to the same limits, entry by entry
+	    let batch = BatchConfig { window: time::Duration::from_millis(20), max_size: 2 };
+	    let upstream = Remote::from(addr.clone()).with_config(ClientConfig { batch: Some(batch), ..Default::default() });
+	    let sends: Vec<_> = (0..2).map(|i| upstream.send(&TestSlow(i)).0.then(Ok::<_, ()>)).collect();
+	    let results = sys.block_on(future::join_all(sends)).unwrap();
+	    assert_eq!(results.iter().filter(|res| res.is_ok()).count(), 1);
+	    let err = results.into_iter().find_map(Result::err).unwrap();
+	    assert_eq!(err.downcast_ref::<RemoteError>().unwrap().status, 503);
+
+	    // Unless the client keeps to them
+	    let limiter = Limiter::new().max_concurrent(1).queue(4);
+	    let upstream = Remote::from(addr).with_config(ClientConfig { limiter: Some(limiter), ..Default::default() });
+	    let sends: Vec<_> = (0..3).map(|i| upstream.send(&TestSlow(i)).0).collect();
+	    assert_eq!(sys.block_on(future::join_all(sends)).unwrap(), vec![0, 1, 2]);
+
+	    // Or routes through a `Limited` to them. The actor only logs
+	    // failures, so its handler is called directly to see them.
+	    let sends = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+	    let handled = sends.clone();
+	    Limited::create(move |ctxt| {
+	        let mut limited = Limited::new(TestHandler::default().start(), Limiter::new().max_concurrent(1));
+	        handled.borrow_mut().extend((0..2).map(|i| Handler::handle(&mut limited, TestSlow(i), ctxt).0.then(Ok::<_, ()>)));
+	        limited
+	    });
+	    let sends = sends.replace(Vec::new());
+	    let results = sys.block_on(future::join_all(sends)).unwrap();
+	    assert_eq!(results.iter().filter(|res| res.is_ok()).count(), 1);
+	    let err = results.into_iter().find_map(Result::err).unwrap();
+	    assert!(err.downcast_ref::<Overloaded>().is_some(), "{}", err);
+	}
+
+	#[test]
+	fn test_outbox() {
+	    use crate::http::{Dedupe, ExposeConfig, RemoteError};
+	    use futures::future;
+	    use std::sync::atomic::Ordering;
+	    init_logger();
+	    let mut sys = System::new("test_client");
+	    let dir = tempfile::tempdir().unwrap();
+	    let path = dir.path().join("outbox");
+	    let (sender, receiver) = mpsc::sync_channel(1);
+	    thread::spawn(move || {
+	        let sys = System::new("test_server");
+	        let app = app::App::new()
+	            .route::<TestSlow, _>(TestHandler::default().start(), RouteType::Server)
+	            .expose_with::<TestSlow>(ExposeConfig { dedupe: Some(Dedupe::default()), ..Default::default() });
+	        let tcp = app.serve_http("127.0.0.1:0").unwrap();
+	        app.make_current();
+	        sender.send(tcp.addr().clone()).unwrap();
+	        sys.run();
+	    });
+	    let addr = receiver.recv().unwrap();
+	    let wait_for = |sys: &mut actix::SystemRunner, done: &dyn Fn() -> bool| {
+	        for _ in 0..100 {
+	            if done() {
+	                return;
+	            }
+	            sys.block_on(tokio::timer::Delay::new(time::Instant::now() + time::Duration::from_millis(20))).unwrap();
+	        }
+	        panic!("timed out");
+	    };
+
+	    // Messages sent again are only handled once
+	    let upstream = Upstream::from(addr.clone());
+	    let sends: Vec<_> = (0..2).map(|_| upstream.send_idempotent(&TestSlow(201), "a".to_string()).0.then(Ok::<_, ()>)).collect();
+	    let results = sys.block_on(future::join_all(sends)).unwrap();
+	    assert_eq!(results.iter().filter(|res| res.is_ok()).count(), 1);
+	    let err = results.into_iter().find_map(Result::err).unwrap();
+	    assert_eq!(err.downcast_ref::<RemoteError>().unwrap().status, 409);
+	    assert_eq!(sys.block_on(upstream.send_idempotent(&TestSlow(201), "a".to_string()).0).unwrap(), 201);
+	    assert_eq!(TEST_SLOWS[201].load(Ordering::SeqCst), 1);
+
+	    // Messages for an unreachable upstream are kept, and delivered by the next run,
+	    // which only gets the outbox once the first is done with it
+	    let dead = Url::parse("http://127.0.0.1:1/").unwrap();
+	    let first_run = {
+	        let (path, dead) = (path.clone(), dead.clone());
+	        thread::spawn(move || {
+	            let mut sys = System::new("test_first_run");
+	            let hour = time::Duration::from_secs(3600);
+	            let outbox = Outbox::open(&path).unwrap().backoff(hour, hour);
+	            assert!(Outbox::open(&path).is_err());
+	            app::App::new()
+	                .route::<TestSlow, _>(Durable::new(dead, outbox.clone()), RouteType::Upstream)
+	                .make_current();
+	            app::notify_out(TestSlow(202)).unwrap();
+	            wait_for(&mut sys, &|| outbox.pending() == 1);
+	        })
+	    };
+	    first_run.join().unwrap();
+
+	    let outbox = Outbox::open(&path).unwrap();
+	    assert_eq!(outbox.pending(), 1);
+	    app::App::new()
+	        .route::<TestSlow, _>(Durable::new(Remote::from(addr), outbox.clone()), RouteType::Upstream)
+	        .make_current();
+	    assert_eq!(sys.block_on(app::send_out(TestSlow(203))).unwrap(), 203);
+	    wait_for(&mut sys, &|| outbox.pending() == 0);
+	    assert_eq!(TEST_SLOWS[202].load(Ordering::SeqCst), 1);
+	    drop(outbox);
+	    app::App::new().make_current();
+	    wait_for(&mut sys, &|| Outbox::open(&path).is_ok());
+
+	    // Until the outbox gives up on them
+	    let ms = time::Duration::from_millis(10);
+	    let outbox = Outbox::open(&path).unwrap().max_attempts(2).backoff(ms, ms);
+	    app::App::new()
+	        .route::<TestSlow, _>(Durable::new(dead, outbox.clone()), RouteType::Upstream)
+	        .make_current();
+	    assert!(sys.block_on(app::send_out(TestSlow(204))).is_err());
+	    assert_eq!(outbox.pending(), 0);
+	    drop(outbox);
+	    app::App::new().make_current();
+	    wait_for(&mut sys, &|| Outbox::open(&path).is_ok());
+
+	    let outbox = Outbox::open(&path).unwrap();
+	    let letters = outbox.dead_letters();
+	    assert_eq!(letters.len(), 1);
+	    assert_eq!(letters[0].attempts, 2);
+	    assert_eq!(letters[0].message::<TestSlow>().unwrap().0, 204);
+	    outbox.discard(letters[0].id).unwrap();
+	    drop(outbox);
+	    assert!(Outbox::open(&path).unwrap().dead_letters().is_empty());
+	}
+
+	#[test]
+	fn test_scatter() {
+	    use crate::ScatterError;
+	    init_logger();
+	    let mut sys = System::new("test_client");
+	    let (sender, receiver) = mpsc::sync_channel(1);
+	    thread::spawn(move || {
+	        let sys = System::new("test_server");
+	        let app = app::App::new().service(TestHandler::default());
+	        let tcp = app.serve_http("127.0.0.1:0").unwrap();
+	        app.make_current();
+	        sender.send(tcp.addr().clone()).unwrap();
+	        sys.run();
+	    });
+	    let addr = receiver.recv().unwrap();
+	    let dead = Url::parse("http://127.0.0.1:1/").unwrap();
+	    // Accepts connections, but never replies
+	    let hung = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	    let hung_url = Url::parse(&format!("http://{}/", hung.local_addr().unwrap())).unwrap();
+
+	    let handler = TestHandler::default().start();
+	    app::App::new()
+	        .group::<TestMessage, _>("ok", handler.clone())
+	        .group::<TestMessage, _>("ok", Remote::from(addr))
+	        .group::<TestMessage, _>("mixed", handler)
+	        .group::<TestMessage, _>("mixed", dead)
+	        .group::<TestMessage, _>("mixed", hung_url)
+	        .make_current();
+	    let timeout = time::Duration::from_millis(200);
+
+	    let replies = sys.block_on(app::send_all("ok", TestMessage(3))).unwrap();
+	    assert_eq!(replies, vec![TestResponse(3), TestResponse(3)]);
+	    let sum = sys.block_on(app::scatter("ok", TestMessage(3)).reduce(0, |sum, resp| sum + resp.0)).unwrap();
+	    assert_eq!(sum, 6);
+
+	    assert!(sys.block_on(app::scatter("mixed", TestMessage(3)).timeout(timeout).gather()).is_err());
+	    let replies = sys.block_on(app::scatter("mixed", TestMessage(3)).timeout(timeout).partial().gather()).unwrap();
+	    assert_eq!(replies.len(), 3);
+	    assert_eq!(replies[0].result.as_ref().unwrap(), &TestResponse(3));
+	    assert!(replies[1].result.is_err());
+	    match replies[2].result.as_ref().unwrap_err().downcast_ref::<ScatterError>() {
+	        Some(ScatterError::Timeout { member }) => assert!(member.contains(&hung.local_addr().unwrap().to_string())),
+	        other => panic!("{:?}", other),
+	    }
+
+	    // Doesn't wait for the hung member
+	    let replies = sys.block_on(app::scatter("mixed", TestMessage(3)).first(1).gather()).unwrap();
+	    assert_eq!(replies.len(), 1);
+	    let err = sys.block_on(app::scatter("mixed", TestMessage(3)).timeout(timeout).first(2).gather()).unwrap_err();
+	    match err.downcast_ref::<ScatterError>() {
+	        Some(ScatterError::NotEnough { got: 1, needed: 2 }) => (),
+	        other => panic!("{:?}", other),
+	    }
+
+	    assert!(sys.block_on(app::send_all("missing", TestMessage(3))).is_err());
+	}
+
+	/// A CA, and a certificate it issued for "localhost" with its key, all PEM encoded.
+	/// They're made by `src/test_certs/generate.sh`.
+	#[cfg(feature = "tls")]
+	fn test_certs() -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+	    (include_bytes!("test_certs/ca.pem").to_vec(),
+	     include_bytes!("test_certs/localhost.pem").to_vec(),
+	     include_bytes!("test_certs/localhost.key").to_vec())
+	}
+
+	#[cfg(feature = "tls")]
+	#[test]
+	fn test_https() {
+	    use crate::http::{ClientConfig, TlsConfig, TlsIdentity};
+	    init_logger();
+	    let mut sys = System::new("test_client");
+	    let (ca, cert, key) = test_certs();
+	    let (sender, receiver) = mpsc::sync_channel(1);
+	    let identity = TlsIdentity::from_pem(&cert, &key);
+	    thread::spawn(move || {
+	        let sys = System::new("test_server");
+	        let app = app::App::new()
+	            .service(TestHandler::default())
+	            .expose::<TestMessage>();
+	        let server = app.serve_https("127.0.0.1:0", &identity).unwrap();
+	        app.make_current();
+	        sender.send(server.addr().clone()).unwrap();
+	        sys.run();
+	    });
+	    let port = match receiver.recv().unwrap() {
+	        crate::http::ListenAddr::Https(addr) => addr.port(),
+	        other => panic!("not an HTTPS server: {}", other),
+	    };
+
+	    let send = |sys: &mut actix::SystemRunner, host: &str, tls: TlsConfig| {
+	        let url = Url::parse(&format!("https://{}:{}/", host, port)).unwrap();
+	        let config = ClientConfig { tls: Some(tls), ..Default::default() };
+	        app::App::new()
+	            .route::<TestMessage, _>(Remote::Http(url).with_config(config), RouteType::Upstream)
+	            .make_current();
+	        sys.block_on(app::send(TestMessage(7)))
+	    };
+	    assert_eq!(send(&mut sys, "localhost", TlsConfig::with_root(&ca)).unwrap(), TestResponse(7));
+	    // Clients can present a certificate too
+	    let tls = TlsConfig { identity: Some(TlsIdentity::from_pem(&cert, &key)), ..TlsConfig::with_root(&ca) };
+	    assert_eq!(send(&mut sys, "localhost", tls).unwrap(), TestResponse(7));
+	    // Not signed by a trusted CA
+	    assert!(send(&mut sys, "localhost", TlsConfig::default()).is_err());
+	    // The certificate isn't valid for the IP address
+	    assert!(send(&mut sys, "127.0.0.1", TlsConfig::with_root(&ca)).is_err());
+	    let tls = TlsConfig { verify_hostname: false, ..TlsConfig::with_root(&ca) };
+	    assert_eq!(send(&mut sys, "127.0.0.1", tls).unwrap(), TestResponse(7));
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_socket_activation() {
+	    use std::os::unix::io::AsRawFd;
+	    use std::os::unix::process::CommandExt;
+	    init_logger();
+	    let mut sys = System::new("test_server");
+	    let dir = tempfile::tempdir().unwrap();
+	    let path = dir.path().join("activated.sock");
+	    let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+	    let fd = listener.as_raw_fd();
+
+	    // Start the plugin the way systemd would: with the socket as fd 3,
+	    // and `LISTEN_PID` set to the plugin's own pid.
+	    let mut cmd = std::process::Command::new("sh");
+	    cmd.arg("-c").arg("export LISTEN_PID=$$ LISTEN_FDS=1; exec \"$0\" \"$@\"")
+	       .arg("./target/debug/test-plugin")
+	       .arg(dir.path().join("host.sock"))
+	       .arg(dir.path().join("unused.sock"));
+	    unsafe {
+	        cmd.pre_exec(move || {
+	            let res = if fd == 3 {
+	                libc::fcntl(fd, libc::F_SETFD, 0)
+	            } else {
+	                libc::dup2(fd, 3)
+	            };
+	            if res == -1 { Err(std::io::Error::last_os_error()) } else { Ok(()) }
+	        });
+	    }
+	    let mut child = cmd.spawn().unwrap();
+
+	    let res = sys.block_on(crate::http::send_local(&TestMessage(8), &path));
+	    let _ = child.kill();
+	    let _ = child.wait();
+	    assert_eq!(res.unwrap(), TestResponse(8));
+	    assert!(!dir.path().join("unused.sock").exists());
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_tcp_activation() {
+	    use std::os::unix::io::IntoRawFd;
+	    init_logger();
+	    let unix_dir = tempfile::tempdir().unwrap();
+	    let unix = std::os::unix::net::UnixListener::bind(unix_dir.path().join("activated.sock")).unwrap();
+	    let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	    let addr = tcp.local_addr().unwrap();
+	    // Passed in as consecutive fds, away from the ones the test harness uses
+	    const START: std::os::unix::io::RawFd = 900;
+	    for (i, fd) in [unix.into_raw_fd(), tcp.into_raw_fd()].iter().enumerate() {
+	        assert_eq!(unsafe { libc::dup2(*fd, START + i as i32) }, START + i as i32);
+	        unsafe { libc::close(*fd) };
+	    }
+
+	    let pid = std::process::id().to_string();
+	    let mut listeners = crate::activation::Listeners::from_vars(|key| match key {
+	        "LISTEN_PID" => Some(pid.clone()),
+	        "LISTEN_FDS" => Some("2".to_string()),
+	        _ => None,
+	    }, START);
+	    let tcp = listeners.take_tcp().unwrap();
+	    assert_eq!(tcp.local_addr().unwrap(), addr);
+	    assert!(listeners.take_tcp().is_none());
+	    assert!(listeners.take_unix().is_some());
+
+	    // Sockets meant for another process are left alone
+	    let mut listeners = crate::activation::Listeners::from_vars(|key| match key {
+	        "LISTEN_PID" => Some("1".to_string()),
+	        "LISTEN_FDS" => Some("2".to_string()),
+	        _ => None,
+	    }, START);
+	    assert!(listeners.take_tcp().is_none());
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_socket_dir() {
+	    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+	    init_logger();
+	    let mut sys = System::new("test_server");
+	    let tmp = tempfile::tempdir().unwrap();
+	    let dir = tmp.path().join("sockets");
+	    let app = app::App::new()
+	        .socket_dir(crate::SocketDir::new(&dir))
+	        .service(TestHandler::default());
+	    let path = app.sock_path("main").unwrap();
+
+	    // A socket left behind by a previous run
+	    std::fs::DirBuilder::new().mode(0o700).create(&dir).unwrap();
+	    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+	    let handle = app.serve_local_http(None).unwrap();
+	    assert_eq!(handle.addr(), &crate::http::ListenAddr::Unix(path.clone()));
+	    let mode = std::fs::metadata(&dir).unwrap().permissions().mode();
+	    assert_eq!(mode & 0o777, 0o700);
+	    app.make_current();
+
+	    let res = sys.block_on(crate::http::send_local(&TestMessage(5), &path));
+	    assert_eq!(res.unwrap(), TestResponse(5));
+
+	    // Sockets are removed along with the app
+	    app::App::new().make_current();
+	    assert!(!path.exists());
+
+	    #[cfg(target_os = "linux")]
+	    {
+	        let prefix = format!("actix-directory-test-{}", std::process::id());
+	        let app = app::App::new()
+	            .socket_dir(crate::SocketDir::abstract_namespace(&prefix))
+	            .service(TestHandler::default());
+	        let path = app.sock_path("main").unwrap();
+	        app.serve_local_http(None).unwrap();
+	        assert!(path.to_str().unwrap().starts_with('@'));
+	        app.make_current();
+
+	        let res = sys.block_on(crate::http::send_local(&TestMessage(6), &path));
+	        assert_eq!(res.unwrap(), TestResponse(6));
+	    }
+	}
+
 	#[cfg(unix)]
 	#[test]
 	fn test_plugin() {
 	    init_logger();
 	    let mut sys = System::new("test_server");
         let addr = TestHandler::default();
-        let plugin = crate::test_helpers::test_plugin();
+        let mut plugin = crate::test_helpers::test_plugin();
+        plugin.messages.push("pid".to_string());
         let mut app = app::App::new()
         				.plugin(plugin);
-       	let socket_addr = app.serve_local_http(None);
+       	let _server = app.serve_local_http(None).unwrap();
         app.make_current();
 
         // Give the plugin time to spin up?
         thread::sleep(time::Duration::from_millis(100));
 
         let msg = crate::OpaqueMessage::try_new("test", &TestMessage(123)).unwrap();
-	    let _res = sys.block_on(app::send(msg)).unwrap();
+	    let res = sys.block_on(app::send(msg)).unwrap();
+	    assert_eq!(res.id, "test_response");
+
+	    let res = sys.block_on(app::send(TestMessage(42))).unwrap();
+	    assert_eq!(res.0, 42);
+
+	    // The plugin is stopped along with the app
+	    let msg = crate::OpaqueMessage::try_new("pid", &()).unwrap();
+	    let pid = sys.block_on(app::send(msg)).unwrap().inner::<u32>().unwrap();
+	    app::App::new().make_current();
+	    assert_ne!(unsafe { libc::kill(pid as libc::pid_t, 0) }, 0);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_plugin_stdio() {
+	    init_logger();
+	    let mut sys = System::new("test_server");
+	    let mut plugin = crate::test_helpers::test_plugin();
+	    plugin.transport = crate::plugin::Transport::Stdio;
+	    app::App::new()
+	        .plugin(plugin)
+	        .make_current();
+
+	    let msg = crate::OpaqueMessage::try_new("test", &TestMessage(123)).unwrap();
+	    let res = sys.block_on(app::send(msg)).unwrap();
+	    assert_eq!(res.id, "test_response");
+
+	    let res = sys.block_on(app::send(TestMessage(42))).unwrap();
+	    assert_eq!(res.0, 42);
+
+	    // A plugin which stops replying fails later requests, and gets its stdin closed
+	    let mut child = std::process::Command::new("sh")
+	        .args(&["-c", "exec >&-; cat >/dev/null"])
+	        .stdin(std::process::Stdio::piped())
+	        .stdout(std::process::Stdio::piped())
+	        .spawn().unwrap();
+	    let (stdin, stdout) = (child.stdin.take().unwrap(), child.stdout.take().unwrap());
+	    let client = crate::plugin::StdioClient::new("mute", stdin, stdout, crate::http::DEFAULT_LIMIT);
+	    for _ in 0..100 {
+	        if child.try_wait().unwrap().is_some() {
+	            break;
+	        }
+	        thread::sleep(time::Duration::from_millis(20));
+	    }
+	    assert!(child.try_wait().unwrap().is_some(), "plugin still running");
+	    let err = sys.block_on(client.send(&TestMessage(1))).unwrap_err();
+	    assert!(err.to_string().contains("not running"), "{}", err);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_plugin_json() {
+	    init_logger();
+	    let mut sys = System::new("test_server");
+	    let plugin = crate::Plugin {
+	        name: "test_plugin_py".to_string(),
+	        version: "^0.1".to_string(),
+	        protocol: crate::plugin::PROTOCOL_VERSION,
+	        exec_path: std::path::PathBuf::from("./src/test_plugin.py"),
+	        opt_args: Vec::new(),
+	        messages: vec!["echo".to_string(), "fail".to_string()],
+	        paths: Vec::new(),
+	        transport: crate::plugin::Transport::Socket,
+	        encoding: crate::http::Encoding::Json,
+	        sandbox: Default::default(),
+	        allow: Default::default(),
+	        reload: false,
+	        handshake_timeout: time::Duration::from_secs(10),
+	        ty: RouteType::Server,
+	    };
+	    app::App::new()
+	        .plugin(plugin)
+	        .make_current();
+
+	    let msg = crate::OpaqueMessage::try_new("echo", &TestMessage(7)).unwrap();
+	    let res = sys.block_on(app::send(msg)).unwrap();
+	    assert_eq!(res.id, "echo_response");
+	    assert_eq!(res.inner::<TestMessage>().unwrap(), TestMessage(7));
+
+	    let msg = crate::OpaqueMessage::try_new("fail", &TestMessage(7)).unwrap();
+	    let res = sys.block_on(app::send(msg));
+	    assert!(res.is_err());
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_plugin_sandbox() {
+	    init_logger();
+	    let mut sys = System::new("test_server");
+	    let mut plugin = crate::test_helpers::test_plugin();
+	    plugin.messages.push("sandbox".to_string());
+	    plugin.sandbox = crate::plugin::Sandbox {
+	        clear_env: true,
+	        working_dir: Some(std::path::PathBuf::from("/")),
+	        limits: crate::plugin::Limits {
+	            cpu_seconds: Some(60),
+	            open_files: Some(64),
+	            ..Default::default()
+	        },
+	        nice: Some(10),
+	        ..Default::default()
+	    };
+	    app::App::new()
+	        .plugin(plugin)
+	        .make_current();
+
+	    let msg = crate::OpaqueMessage::try_new("test", &TestMessage(123)).unwrap();
+	    let res = sys.block_on(app::send(msg)).unwrap();
+	    assert_eq!(res.id, "test_response");
+
+	    let msg = crate::OpaqueMessage::try_new("sandbox", ()).unwrap();
+	    let res = sys.block_on(app::send(msg)).unwrap();
+	    let report = res.inner::<crate::test_helpers::SandboxReport>().unwrap();
+	    assert_eq!(report.open_files, 64);
+	    assert_eq!(report.working_dir, std::path::PathBuf::from("/"));
+	    assert!(!report.env.iter().any(|key| key == "PATH" || key == "HOME"), "{:?}", report.env);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_plugin_callback() {
+	    init_logger();
+	    let mut sys = System::new("test_server");
+	    let mut plugin = crate::test_helpers::test_plugin();
+	    plugin.messages.push("callback".to_string());
+	    plugin.allow.paths.push(TestMessage::PATH.to_string());
+	    app::App::new()
+	        .route::<TestMessage, _>(TestHandler::start_default(), RouteType::Server)
+	        .plugin(plugin)
+	        .make_current();
+
+	    let msg = crate::OpaqueMessage::try_new("callback", &TestMessage(9)).unwrap();
+	    let res = sys.block_on(app::send(msg)).unwrap();
+	    assert_eq!(res.id, "callback_response");
+	    assert_eq!(res.inner::<TestResponse>().unwrap(), TestResponse(9));
+
+	    // The same plugin without permission to send `TestMessage`
+	    let mut plugin = crate::test_helpers::test_plugin();
+	    plugin.name = "test_plugin_denied".to_string();
+	    plugin.messages = vec!["callback".to_string()];
+	    plugin.paths = Vec::new();
+	    let app = app::App::new()
+	        .route::<TestMessage, _>(TestHandler::start_default(), RouteType::Server);
+	    let host = app.sockets().path("test_plugin_denied.host").unwrap();
+	    app.plugin(plugin).make_current();
+
+	    let msg = crate::OpaqueMessage::try_new("callback", &TestMessage(9)).unwrap();
+	    let res = sys.block_on(app::send(msg));
+	    assert!(res.is_err());
+
+	    // Sent straight to the host's callback server, as the plugin does
+	    let res = sys.block_on(crate::http::send_local(&TestMessage(9), &host));
+	    match res {
+	        Err(err) => match err.downcast_ref::<crate::http::RemoteError>() {
+	            Some(err) => {
+	                assert_eq!(err.status, 403);
+	                assert!(err.message.contains("not allowed"), "{}", err.message);
+	            },
+	            None => panic!("{}", err),
+	        },
+	        Ok(_) => panic!("denied callback was handled"),
+	    }
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_plugin_reload() {
+	    init_logger();
+	    let mut sys = System::new("test_server");
+	    let dir = tempfile::tempdir().unwrap();
+	    let exec_path = dir.path().join("test-plugin");
+	    std::fs::copy("./target/debug/test-plugin", &exec_path).unwrap();
+	    let mut plugin = crate::test_helpers::test_plugin();
+	    plugin.name = "test_plugin_reload".to_string();
+	    plugin.exec_path = exec_path.clone();
+	    plugin.messages.push("pid".to_string());
+	    plugin.reload = true;
+	    app::App::new()
+	        .plugin(plugin)
+	        .make_current();
+
+	    let pid = || {
+	        let msg = crate::OpaqueMessage::try_new("pid", &()).unwrap();
+	        app::send(msg).and_then(|res| res.inner::<u32>())
+	    };
+	    let old = sys.block_on(pid()).unwrap();
+
+	    // Replace the executable the way a build would, without writing to the running one
+	    let tmp = dir.path().join("test-plugin.new");
+	    std::fs::copy("./target/debug/test-plugin", &tmp).unwrap();
+	    thread::sleep(time::Duration::from_millis(10));
+	    std::fs::rename(&tmp, &exec_path).unwrap();
+
+	    let wait = tokio::timer::Delay::new(time::Instant::now() + time::Duration::from_secs(4));
+	    sys.block_on(wait).unwrap();
+	    let new = sys.block_on(pid()).unwrap();
+	    assert_ne!(old, new);
+	    // The old process was stopped once it had no requests left
+	    assert_ne!(unsafe { libc::kill(old as libc::pid_t, 0) }, 0);
+
+	    // And the new one once the app is gone
+	    app::App::new().make_current();
+	    let wait = tokio::timer::Delay::new(time::Instant::now() + time::Duration::from_millis(100));
+	    sys.block_on(wait).unwrap();
+	    assert_ne!(unsafe { libc::kill(new as libc::pid_t, 0) }, 0);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_plugin_output() {
+	    use crate::plugin::output::parse;
+	    use log::Level;
+	    assert_eq!(parse("[2019-01-01T00:00:00Z INFO  test_plugin] Starting plugin"), Some((Level::Info, "test_plugin", "Starting plugin")));
+	    assert_eq!(parse("[2019-01-01T00:00:00Z ERROR test_plugin::handler] failed: [3]"), Some((Level::Error, "test_plugin::handler", "failed: [3]")));
+	    // The target is left out for the root module
+	    assert_eq!(parse("[2019-01-01T00:00:00Z WARN ] low on memory"), Some((Level::Warn, "", "low on memory")));
+	    assert_eq!(parse("plain output"), None);
+	    assert_eq!(parse("[not a header] text"), None);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_plugin_incompatible() {
+	    init_logger();
+	    let mut sys = System::new("test_server");
+	    let mut plugin = crate::test_helpers::test_plugin();
+	    plugin.version = ">=1.0".to_string();
+	    app::App::new()
+	        .plugin(plugin.clone())
+	        .make_current();
+
+	    let msg = crate::OpaqueMessage::try_new("test", &TestMessage(123)).unwrap();
+	    let res = sys.block_on(app::send(msg));
+	    assert!(res.is_err());
+
+	    // Refused for the version it reports
+	    let dir = tempfile::tempdir().unwrap();
+	    let instance = plugin.spawn(&dir.path().join("host.sock"), dir.path().join("plugin.sock")).unwrap();
+	    let res = sys.block_on(plugin.connect(instance.upstream.clone()));
+	    drop(instance);
+	    match res {
+	        Err(crate::plugin::PluginError::Version { found, required, .. }) => {
+	            assert_eq!(found, env!("CARGO_PKG_VERSION"));
+	            assert_eq!(required, ">=1.0");
+	        },
+	        Err(other) => panic!("{}", other),
+	        Ok(_) => panic!("incompatible plugin was accepted"),
+	    }
+
+	    // Plugins which never listen are given up on after the handshake timeout
+	    let mut plugin = crate::test_helpers::test_plugin().handshake_timeout(time::Duration::from_millis(200));
+	    plugin.exec_path = std::path::PathBuf::from("/bin/false");
+	    let instance = plugin.spawn(&dir.path().join("host.sock"), dir.path().join("missing.sock")).unwrap();
+	    let start = time::Instant::now();
+	    let res = sys.block_on(plugin.connect(instance.upstream.clone()));
+	    assert!(start.elapsed() < time::Duration::from_secs(1), "{:?}", start.elapsed());
+	    match res {
+	        Err(crate::plugin::PluginError::Handshake { .. }) => (),
+	        other => panic!("{:?}", other.map(|_| ())),
+	    }
+
+	    // While an error reply ends the handshake straight away
+	    let socket = dir.path().join("not_a_plugin.sock");
+	    let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+	    thread::spawn(move || {
+	        use std::io::{Read, Write};
+	        let (mut conn, _) = listener.accept().unwrap();
+	        let mut buf = [0u8; 1024];
+	        let _ = conn.read(&mut buf);
+	        let _ = conn.write_all(b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n");
+	    });
+	    let plugin = crate::test_helpers::test_plugin();
+	    let start = time::Instant::now();
+	    let res = sys.block_on(plugin.connect(crate::router::Remote::LocalHttp(socket).into()));
+	    assert!(start.elapsed() < time::Duration::from_secs(1), "{:?}", start.elapsed());
+	    match res {
+	        Err(crate::plugin::PluginError::Handshake { cause, .. }) => assert!(cause.contains("404"), "{}", cause),
+	        other => panic!("{:?}", other.map(|_| ())),
+	    }
 	}
 }
 
@@ -0,0 +1,234 @@
+//! Rate limits and bulkheads, to keep one slow or busy handler from
+//! piling up requests without bound.
+
+use actix::Arbiter;
+use failure::{Error, Fail};
+use futures::{future, sync::oneshot, Future};
+use log::*;
+use tokio::timer::Delay;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A request was turned away, since its limits were reached and
+/// too many others were already waiting.
+#[derive(Debug, Fail)]
+#[fail(display = "overloaded, {} requests are already waiting", waiting)]
+pub struct Overloaded {
+    pub waiting: usize,
+}
+
+/// Longest a waiter sleeps before checking the bucket again, for very low rates.
+const MAX_WAIT_SECS: f64 = 24.0 * 60.0 * 60.0;
+
+struct Rate {
+    per_second: f64,
+    burst: f64,
+}
+
+struct State {
+    rate: Option<Rate>,
+    max_concurrent: Option<usize>,
+    queue: usize,
+    /// Left in the bucket as of `refilled`.
+    tokens: f64,
+    refilled: Instant,
+    in_flight: usize,
+    waiting: VecDeque<oneshot::Sender<Permit>>,
+    /// Whether a timer will grant the next waiter once there's a token for it.
+    timer: bool,
+}
+
+impl State {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        if let Some(rate) = &self.rate {
+            let elapsed = now.duration_since(self.refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate.per_second).min(rate.burst);
+        }
+        self.refilled = now;
+    }
+
+    fn has_token(&self) -> bool {
+        self.rate.is_none() || self.tokens >= 1.0
+    }
+
+    fn has_slot(&self) -> bool {
+        self.max_concurrent.map_or(true, |max| self.in_flight < max)
+    }
+
+    fn take(&mut self) {
+        if self.rate.is_some() {
+            self.tokens -= 1.0;
+        }
+        self.in_flight += 1;
+    }
+
+    /// How long until the bucket has a token again, up to a day.
+    fn next_token(&self) -> Option<Duration> {
+        self.rate.as_ref().map(|rate| {
+            let secs = (1.0 - self.tokens).max(0.0) / rate.per_second;
+            Duration::from_secs_f64(secs.min(MAX_WAIT_SECS))
+        })
+    }
+}
+
+/// Limits the requests going through it: how many can start per second,
+/// how many can be in flight at once, and how many can wait for either.
+///
+/// It's shared by its clones, so the same limits can cover a route, a message
+/// type, a remote or a whole server, depending on where they are used.
+/// Without any limits set, every request goes through right away.
+#[derive(Clone)]
+pub struct Limiter(Arc<Mutex<State>>);
+
+impl fmt::Debug for Limiter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.0.lock().unwrap();
+        f.debug_struct("Limiter")
+            .field("in_flight", &state.in_flight)
+            .field("waiting", &state.waiting.len())
+            .finish()
+    }
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Limiter {
+    pub fn new() -> Self {
+        Limiter(Arc::new(Mutex::new(State {
+            rate: None,
+            max_concurrent: None,
+            queue: 0,
+            tokens: 0.0,
+            refilled: Instant::now(),
+            in_flight: 0,
+            waiting: VecDeque::new(),
+            timer: false,
+        })))
+    }
+
+    /// Start at most `per_second` requests a second on average,
+    /// and up to `burst` at once after a quiet period.
+    ///
+    /// Panics unless `per_second` is positive.
+    pub fn rate(self, per_second: f64, burst: u32) -> Self {
+        assert!(per_second > 0.0, "Limiter rate must be positive, not {}", per_second);
+        {
+            let mut state = self.0.lock().unwrap();
+            state.rate = Some(Rate { per_second, burst: f64::from(burst.max(1)) });
+            state.tokens = f64::from(burst.max(1));
+        }
+        self
+    }
+
+    /// Have at most `max` requests in flight at once.
+    pub fn max_concurrent(self, max: usize) -> Self {
+        self.0.lock().unwrap().max_concurrent = Some(max);
+        self
+    }
+
+    /// Let up to `queue` requests wait for the limits, rather than failing
+    /// with `Overloaded` right away. Defaults to none.
+    pub fn queue(self, queue: usize) -> Self {
+        self.0.lock().unwrap().queue = queue;
+        self
+    }
+
+    /// Requests which were let through and haven't finished yet.
+    pub fn in_flight(&self) -> usize {
+        self.0.lock().unwrap().in_flight
+    }
+
+    /// Wait for the limits to let one more request through. The permit
+    /// counts towards them until it's dropped.
+    pub fn acquire(&self) -> impl Future<Item=Permit, Error=Error> {
+        let limiter = self.clone();
+        future::lazy(move || {
+            let mut state = limiter.0.lock().unwrap();
+            state.refill();
+            state.waiting.retain(|tx| !tx.is_canceled());
+            if state.waiting.is_empty() && state.has_token() && state.has_slot() {
+                state.take();
+                return future::Either::A(future::ok(Permit(limiter.clone())));
+            }
+            if state.waiting.len() >= state.queue {
+                warn!("Rejecting request, {} are already waiting", state.waiting.len());
+                return future::Either::A(future::err(Overloaded { waiting: state.waiting.len() }.into()));
+            }
+            let (tx, rx) = oneshot::channel();
+            state.waiting.push_back(tx);
+            limiter.schedule(&mut state);
+            future::Either::B(rx.map_err(|_| failure::err_msg("limiter was dropped")))
+        })
+    }
+
+    /// Let the waiters through while the limits allow it.
+    fn wake(&self) {
+        let mut granted = Vec::new();
+        {
+            let mut state = self.0.lock().unwrap();
+            state.refill();
+            while state.has_token() && state.has_slot() {
+                match state.waiting.pop_front() {
+                    Some(tx) => {
+                        state.take();
+                        granted.push(tx);
+                    },
+                    None => break,
+                }
+            }
+            self.schedule(&mut state);
+        }
+        // Outside of the lock, since the permits of cancelled waiters are dropped right away
+        for tx in granted {
+            let _ = tx.send(Permit(self.clone()));
+        }
+    }
+
+    /// Wake the waiters once there's a token for the first of them.
+    fn schedule(&self, state: &mut State) {
+        if state.timer || state.waiting.is_empty() || state.has_token() {
+            return;
+        }
+        if let Some(wait) = state.next_token() {
+            state.timer = true;
+            let limiter = self.clone();
+            Arbiter::spawn(Delay::new(Instant::now() + wait).then(move |_| {
+                limiter.0.lock().unwrap().timer = false;
+                limiter.wake();
+                Ok(())
+            }));
+        }
+    }
+}
+
+/// Lets one request through a `Limiter`, until dropped.
+#[derive(Debug)]
+pub struct Permit(Limiter);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.0.lock().unwrap().in_flight -= 1;
+        self.0.wake();
+    }
+}
+
+/// Run `fut` within the limits of `limiter`, if there is one.
+pub(crate) fn limited<F>(limiter: Option<&Limiter>, fut: F) -> Box<dyn Future<Item=F::Item, Error=Error>>
+    where F: 'static + Future<Error=Error>,
+{
+    match limiter {
+        Some(limiter) => Box::new(limiter.acquire().and_then(move |permit| fut.then(move |res| {
+            drop(permit);
+            res
+        }))),
+        None => Box::new(fut),
+    }
+}
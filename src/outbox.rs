@@ -0,0 +1,338 @@
+//! Messages kept on disk until an upstream has them, so they outlive an
+//! unreachable upstream, or the app itself.
+//!
+//! The outbox is an append-only journal of records, each prefixed by its
+//! length as 4 big-endian bytes. It's rewritten with only the messages
+//! still pending, or dead, whenever it's opened. While it's open, the
+//! outbox holds a lock on a file next to it, named after it with `.lock` on the
+//! end, so only one app uses it at a time.
+
+use failure::Error;
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::MessageExt;
+use crate::http::RemoteError;
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Record {
+    Queued {
+        id: u64,
+        key: String,
+        path: String,
+        #[serde(with = "serde_bytes")]
+        body: Vec<u8>,
+    },
+    /// Delivered, or discarded from the dead letters.
+    Done { id: u64 },
+    Dead { id: u64, attempts: u32, error: String },
+}
+
+/// A message the outbox gave up on delivering.
+#[derive(Clone, Debug)]
+pub struct DeadLetter {
+    pub id: u64,
+    /// The `MessageExt::PATH` of the message.
+    pub path: String,
+    /// The idempotency key it was sent with.
+    pub key: String,
+    pub attempts: u32,
+    /// The last failure to deliver it.
+    pub error: String,
+    body: Vec<u8>,
+}
+
+impl DeadLetter {
+    /// The message itself, so it can be sent again.
+    pub fn message<M: MessageExt>(&self) -> Result<M, Error> {
+        if self.path != M::PATH {
+            return Err(failure::err_msg(format!("dead letter {} is for path {:?}, not {:?}", self.id, self.path, M::PATH)));
+        }
+        crate::deserialize(&self.body)
+    }
+}
+
+struct Entry {
+    key: String,
+    path: String,
+    body: Vec<u8>,
+    /// Whether a route is delivering it already.
+    active: bool,
+}
+
+struct State {
+    path: PathBuf,
+    file: File,
+    /// Locked for as long as the outbox is open.
+    _lock: File,
+    next: u64,
+    /// Distinguishes the keys of messages queued since opening from earlier ones,
+    /// whose ids may be reused once the journal is rewritten.
+    epoch: u128,
+    pending: BTreeMap<u64, Entry>,
+    dead: BTreeMap<u64, DeadLetter>,
+    max_attempts: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl State {
+    fn append(&mut self, record: &Record) -> Result<(), Error> {
+        let body = crate::serialize(record)?;
+        let mut buf = Vec::with_capacity(4 + body.len());
+        buf.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&body);
+        self.file.write_all(&buf)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Append `record`, logging failures: the message may then be delivered
+    /// again after a restart, which the idempotency key covers.
+    fn append_or_log(&mut self, record: &Record) {
+        if let Err(err) = self.append(record) {
+            error!("Failed to write to outbox {:?}: {}", self.path, err);
+        }
+    }
+}
+
+/// Messages waiting to be delivered to upstreams, kept in a file.
+/// Shared between clones.
+///
+/// Messages get to an outbox through `Durable` routes, which keep trying
+/// to deliver them, backing off between attempts, until the upstream replies
+/// or the outbox gives up on them. Each is sent with an idempotency key, so
+/// servers with `ExposeConfig::dedupe` handle it once, however many times it's sent.
+#[derive(Clone)]
+pub struct Outbox(Arc<Mutex<State>>);
+
+impl fmt::Debug for Outbox {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.0.lock().unwrap();
+        f.debug_struct("Outbox")
+            .field("path", &state.path)
+            .field("pending", &state.pending.len())
+            .field("dead", &state.dead.len())
+            .finish()
+    }
+}
+
+impl Outbox {
+    /// Open the outbox at `path`, creating it if needed. Messages left
+    /// pending are delivered again once routes for them are started.
+    ///
+    /// By default, each message is tried up to 10 times, waiting from a
+    /// second up to 5 minutes in between.
+    ///
+    /// Fails if the outbox is open already, here or in another process.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let lock = lock_journal(&path)?;
+        let (mut pending, mut dead) = (BTreeMap::new(), BTreeMap::new());
+        let mut next = 0;
+        for record in read_journal(&path)? {
+            match record {
+                Record::Queued { id, key, path, body } => {
+                    next = next.max(id + 1);
+                    pending.insert(id, Entry { key, path, body, active: false });
+                },
+                Record::Done { id } => {
+                    pending.remove(&id);
+                    dead.remove(&id);
+                },
+                Record::Dead { id, attempts, error } => if let Some(entry) = pending.remove(&id) {
+                    dead.insert(id, DeadLetter { id, path: entry.path, key: entry.key, attempts, error, body: entry.body });
+                },
+            }
+        }
+        let file = rewrite_journal(&path, &pending, &dead)?;
+        debug!("Opened outbox {:?}, with {} messages pending and {} dead", path, pending.len(), dead.len());
+        Ok(Outbox(Arc::new(Mutex::new(State {
+            path,
+            file,
+            _lock: lock,
+            next,
+            epoch: SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_nanos()).unwrap_or_default(),
+            pending,
+            dead,
+            max_attempts: 10,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5 * 60),
+        }))))
+    }
+
+    /// Give up on a message after `max_attempts` to deliver it.
+    pub fn max_attempts(self, max_attempts: u32) -> Self {
+        self.0.lock().unwrap().max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Wait `initial` after the first failure to deliver a message, doubling
+    /// it after each of the next, up to `max`.
+    pub fn backoff(self, initial: Duration, max: Duration) -> Self {
+        {
+            let mut state = self.0.lock().unwrap();
+            state.backoff = initial;
+            state.max_backoff = max;
+        }
+        self
+    }
+
+    /// The number of messages which haven't been delivered yet.
+    pub fn pending(&self) -> usize {
+        self.0.lock().unwrap().pending.len()
+    }
+
+    /// The messages the outbox gave up on, oldest first.
+    pub fn dead_letters(&self) -> Vec<DeadLetter> {
+        self.0.lock().unwrap().dead.values().cloned().collect()
+    }
+
+    /// Forget a dead letter, such as once it's been sent again.
+    pub fn discard(&self, id: u64) -> Result<(), Error> {
+        let mut state = self.0.lock().unwrap();
+        if state.dead.remove(&id).is_none() {
+            return Err(failure::err_msg(format!("no dead letter {} in outbox {:?}", id, state.path)));
+        }
+        state.append(&Record::Done { id })
+    }
+
+    /// Keep `msg` until it's delivered. Returns its id and idempotency key.
+    pub(crate) fn push<M: MessageExt>(&self, msg: &M) -> Result<(u64, String), Error> {
+        let body = crate::serialize(msg)?;
+        let mut state = self.0.lock().unwrap();
+        let id = state.next;
+        let key = format!("{:x}-{:x}", state.epoch, id);
+        state.append(&Record::Queued { id, key: key.clone(), path: M::PATH.to_string(), body: body.clone() })?;
+        state.next += 1;
+        state.pending.insert(id, Entry { key: key.clone(), path: M::PATH.to_string(), body, active: true });
+        Ok((id, key))
+    }
+
+    /// Take over the pending messages for `path` which nothing is delivering yet,
+    /// as their id, idempotency key and serialized message.
+    pub(crate) fn resume(&self, path: &str) -> Vec<(u64, String, Vec<u8>)> {
+        let mut state = self.0.lock().unwrap();
+        state.pending.iter_mut()
+            .filter(|(_, entry)| entry.path == path && !entry.active)
+            .map(|(&id, entry)| {
+                entry.active = true;
+                (id, entry.key.clone(), entry.body.clone())
+            })
+            .collect()
+    }
+
+    pub(crate) fn delivered(&self, id: u64) {
+        let mut state = self.0.lock().unwrap();
+        if state.pending.remove(&id).is_some() {
+            state.append_or_log(&Record::Done { id });
+        }
+    }
+
+    /// Record that `attempts` to deliver a message have failed, the last with `err`.
+    /// Returns how long to wait before the next attempt, or `None` once it's dead.
+    pub(crate) fn failed(&self, id: u64, attempts: u32, err: &Error) -> Option<Duration> {
+        let mut state = self.0.lock().unwrap();
+        if attempts < state.max_attempts && !is_permanent(err) {
+            let wait = state.backoff.checked_mul(1 << (attempts - 1).min(16)).unwrap_or(state.max_backoff).min(state.max_backoff);
+            warn!("Failed to deliver message {} from outbox {:?}, retrying in {:?}: {}", id, state.path, wait, err);
+            return Some(wait);
+        }
+        error!("Giving up on message {} from outbox {:?} after {} attempts: {}", id, state.path, attempts, err);
+        if let Some(entry) = state.pending.remove(&id) {
+            let error = err.to_string();
+            state.append_or_log(&Record::Dead { id, attempts, error: error.clone() });
+            state.dead.insert(id, DeadLetter { id, path: entry.path, key: entry.key, attempts, error, body: entry.body });
+        }
+        None
+    }
+}
+
+/// Whether the remote rejected the message itself, so sending it again won't help.
+fn is_permanent(err: &Error) -> bool {
+    match err.downcast_ref::<RemoteError>() {
+        Some(err) => (400..500).contains(&err.status) && ![408, 409, 429].contains(&err.status),
+        None => false,
+    }
+}
+
+/// Take the lock on the journal at `path`, failing if it's held already.
+fn lock_journal(path: &Path) -> Result<File, Error> {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let lock = OpenOptions::new().write(true).create(true).truncate(false).open(&lock_path)?;
+    #[cfg(unix)]
+    if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::WouldBlock {
+            return Err(failure::err_msg(format!("outbox {:?} is open already", path)));
+        }
+        return Err(err.into());
+    }
+    Ok(lock)
+}
+
+/// The records of the journal at `path`, up to any partly written one.
+fn read_journal(path: &Path) -> Result<Vec<Record>, Error> {
+    let mut journal = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => file.read_to_end(&mut journal)?,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut records = Vec::new();
+    let mut rest = &journal[..];
+    while rest.len() >= 4 {
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        if rest.len() < 4 + len {
+            break;
+        }
+        records.push(crate::deserialize(&rest[4..4 + len])
+            .map_err(|err| failure::err_msg(format!("corrupt record in outbox {:?}: {}", path, err)))?);
+        rest = &rest[4 + len..];
+    }
+    if !rest.is_empty() {
+        warn!("Ignoring a partly written record at the end of outbox {:?}", path);
+    }
+    Ok(records)
+}
+
+/// Replace the journal at `path` with one for `pending` and `dead`,
+/// and open it for appending.
+fn rewrite_journal(path: &Path, pending: &BTreeMap<u64, Entry>, dead: &BTreeMap<u64, DeadLetter>) -> Result<File, Error> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    let queued = pending.iter()
+        .map(|(&id, entry)| (id, &entry.key, &entry.path, &entry.body))
+        .chain(dead.values().map(|letter| (letter.id, &letter.key, &letter.path, &letter.body)));
+    let mut records: Vec<Record> = queued
+        .map(|(id, key, path, body)| Record::Queued { id, key: key.clone(), path: path.clone(), body: body.clone() })
+        .collect();
+    records.extend(dead.values().map(|letter| Record::Dead { id: letter.id, attempts: letter.attempts, error: letter.error.clone() }));
+    for record in &records {
+        let body = crate::serialize(record)?;
+        tmp.write_all(&(body.len() as u32).to_be_bytes())?;
+        tmp.write_all(&body)?;
+    }
+    tmp.as_file().sync_all()?;
+    tmp.persist(path).map_err(|err| err.error)?;
+    // So the rename outlives a crash too
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    Ok(OpenOptions::new().append(true).open(path)?)
+}
+
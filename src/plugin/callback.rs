@@ -0,0 +1,144 @@
+//! Requests made by a plugin back to the host.
+//!
+//! Every plugin gets its own callback socket, which is passed as its first
+//! argument. Requests arriving there are checked against the plugin's `Acl`
+//! before being handled by the host's current `App`, as `ClientIn` would.
+
+use ::actix::prelude::*;
+use actix_web::{http, AsyncResponder, HttpMessage, HttpRequest, HttpResponse};
+use failure::{Error, Fail};
+use futures::Future;
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{app, MessageExt, OpaqueMessage};
+use crate::http::Encoding;
+
+/// The messages a plugin is allowed to send back to the host.
+/// Anything not listed is rejected.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Acl {
+    /// `MessageExt::PATH`s of typed messages. `"*"` allows every typed message.
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Ids of `OpaqueMessage`s. `"*"` allows every id.
+    #[serde(default)]
+    pub ids: Vec<String>,
+}
+
+impl Acl {
+    fn allows(list: &[String], item: &str) -> bool {
+        list.iter().any(|allowed| allowed == "*" || allowed == item)
+    }
+}
+
+/// A plugin tried to send a message it isn't allowed to.
+#[derive(Debug, Fail)]
+#[fail(display = "plugin {} is not allowed to send {}", name, message)]
+pub struct AccessDenied {
+    pub name: String,
+    pub message: String,
+}
+
+/// A serialized message sent by a plugin.
+struct Callback {
+    path: String,
+    body: Vec<u8>,
+}
+
+impl Message for Callback {
+    type Result = Result<Vec<u8>, Error>;
+}
+
+/// Checks and handles callbacks on the host's thread, since that's
+/// where the current `App` lives.
+struct CallbackHandler {
+    name: String,
+    acl: Acl,
+}
+
+impl Actor for CallbackHandler {
+    type Context = Context<Self>;
+}
+
+impl CallbackHandler {
+    fn authorize(&self, path: &str, body: &[u8]) -> Result<(), Error> {
+        let (allowed, message) = if path == OpaqueMessage::PATH {
+            let id = crate::deserialize::<OpaqueMessage>(body)?.id;
+            (Acl::allows(&self.acl.ids, &id), format!("message id {:?}", id))
+        } else {
+            (Acl::allows(&self.acl.paths, path), format!("messages on path /{}", path))
+        };
+        if allowed {
+            Ok(())
+        } else {
+            let err = AccessDenied { name: self.name.clone(), message };
+            warn!("{}", err);
+            Err(err.into())
+        }
+    }
+}
+
+impl Handler<Callback> for CallbackHandler {
+    type Result = ResponseFuture<Vec<u8>, Error>;
+
+    fn handle(&mut self, msg: Callback, _ctxt: &mut Context<Self>) -> Self::Result {
+        trace!("Callback from plugin {} on path {:?}", self.name, msg.path);
+        match self.authorize(&msg.path, &msg.body) {
+            Ok(()) => app::APP.with(|app| app.borrow().dispatch_raw(&msg.path, &msg.body)),
+            Err(err) => Box::new(futures::future::err(err)),
+        }
+    }
+}
+
+/// Convert a request body to CBOR, which is what the host's handlers expect.
+fn to_cbor(encoding: Encoding, body: &[u8]) -> Result<Vec<u8>, Error> {
+    match encoding {
+        Encoding::Cbor => Ok(body.to_vec()),
+        Encoding::Json => crate::serialize(&serde_json::from_slice::<serde_json::Value>(body)?),
+    }
+}
+
+/// Convert a CBOR response into the encoding of the request.
+fn from_cbor(encoding: Encoding, body: &[u8]) -> Result<Vec<u8>, Error> {
+    match encoding {
+        Encoding::Cbor => Ok(body.to_vec()),
+        Encoding::Json => encoding.serialize(&crate::deserialize::<serde_cbor::Value>(body)?),
+    }
+}
+
+fn handle_callback(req: HttpRequest<Addr<CallbackHandler>>) -> impl actix_web::Responder {
+    let addr = req.state().clone();
+    let encoding = Encoding::of(&req);
+    let path = req.path().trim_start_matches('/').to_string();
+    req.body().map_err(Error::from)
+        .and_then(move |body| to_cbor(encoding, &body))
+        .and_then(move |body| addr.send(Callback { path, body }).map_err(Error::from).flatten())
+        .and_then(move |resp| from_cbor(encoding, &resp))
+        .then(move |res| -> Result<HttpResponse, Error> {
+            match res {
+                Ok(resp) => Ok(HttpResponse::Ok().content_type(encoding.content_type()).body(resp)),
+                Err(err) => {
+                    let mut resp = crate::http::error_response(encoding, &err);
+                    if err.downcast_ref::<AccessDenied>().is_some() {
+                        *resp.status_mut() = http::StatusCode::FORBIDDEN;
+                    }
+                    Ok(resp)
+                },
+            }
+        })
+        .responder()
+}
+
+/// Serve callbacks from the plugin `name` on `listener`.
+pub(crate) fn serve(name: &str, acl: Acl, listener: tokio_uds::UnixListener) {
+    let addr = CallbackHandler { name: name.to_string(), acl }.start();
+    #[allow(deprecated)]
+    actix_web::server::new(move || {
+        actix_web::App::with_state(addr.clone())
+            .route("/{path:.*}", http::Method::POST, handle_callback)
+            .middleware(actix_web::middleware::Logger::default())
+    })
+    .workers(1)
+    .start_incoming(listener.incoming(), false);
+}
@@ -1,37 +1,180 @@
-use std::process::Command;
+use ::actix::{Actor, Arbiter};
+use failure::Error;
+use futures::Future;
+use log::Level;
 
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use crate::http::ClientConfig;
 use crate::prelude::*;
+use crate::router::PathRoute;
+use super::{callback, handshake, output, reload, stdio, Transport};
+
+/// A running plugin process, and how to reach it.
+/// The process is killed once this is dropped.
+pub(crate) struct Instance {
+    pub(crate) child: Child,
+    pub(crate) upstream: Upstream,
+    /// The socket the plugin listens on.
+    pub(crate) socket: PathBuf,
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.socket);
+    }
+}
 
 impl Plugin {
-    pub fn add_to(self, mut app: crate::App) -> crate::App {
+    /// Give the plugin `timeout` to start listening and answer the handshake.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    pub fn add_to(mut self, mut app: crate::App) -> crate::App {
         // let socket2 = socket.clone();
         log::trace!("Adding plugin: {:?}", self);
-        let Plugin {
-            name,
-            // version,
-            exec_path,
-            messages,
-            opt_args,
-            ty
-        } = self;
-        let sin = crate::app::sock_path("main");
-        // let sin2 = socket.clone();
-        let sout = crate::app::sock_path(&name);
-        let sout2 = sout.clone();
-        // let name2 = name.clone();
-        let mut cmd = Command::new(&exec_path);
-        if let Some(s) = std::env::var_os("TEST_LOG") {
-            cmd.env("TEST_LOG", s);
-        }
-        let _res = cmd.arg(&sin.to_str().unwrap_or("/dev/null"))
-                      .arg(&sout2.to_str().unwrap_or("/dev/null"))
-                      .args(opt_args)
-                      .spawn().expect("Failed to start plugin");
-
-        for msg in messages.iter() {
-            let route = (msg.as_str(), sout.clone());
+        let sockets = app.sockets().clone();
+        let (host, socket) = match (sockets.path(&format!("{}.host", self.name)), sockets.path(&self.name)) {
+            (Ok(host), Ok(socket)) => (host, socket),
+            (Err(err), _) | (_, Err(err)) => {
+                log::error!("Not adding plugin {}, no socket for it: {}", self.name, err);
+                return app;
+            },
+        };
+        match sockets.bind(&host) {
+            Ok(listener) => callback::serve(&self.name, self.allow.clone(), listener),
+            Err(err) => log::error!("Failed to serve callbacks for plugin {}: {}", self.name, err),
+        }
+        // The sandbox may change the working directory, so don't
+        // leave a relative path to be resolved against it.
+        self.exec_path = std::fs::canonicalize(&self.exec_path).unwrap_or(self.exec_path);
+        sockets.remove_on_drop(&socket);
+        let instance = match self.spawn(&host, socket) {
+            Ok(instance) => instance,
+            Err(err) => {
+                log::error!("Failed to start plugin {}: {}", self.name, err);
+                return app;
+            },
+        };
+
+        // Messages are held by a `PendingRoute` until the plugin has
+        // answered the handshake with a compatible version.
+        let (tx, rx) = futures::sync::oneshot::channel();
+        Arbiter::spawn(self.connect(instance.upstream.clone()).then(move |res| {
+            let _ = tx.send(res);
+            Ok(())
+        }));
+        let ready = rx.shared();
+        let ty = self.ty;
+
+        for msg in self.messages.iter() {
+            let ready = ready.clone()
+                .map_err(|_| Error::from(crate::router::RouterError::default()))
+                .and_then(|res| (*res).clone().map_err(Error::from));
+            let route = (msg.as_str(), PendingRoute::new(ready));
             app = app.route(route, ty);
         }
+
+        // Typed messages are routed by their path, since the host
+        // doesn't know their types.
+        if !self.paths.is_empty() {
+            let pending = ready.clone()
+                .map_err(|_| Error::from(crate::router::RouterError::default()))
+                .and_then(|res| (*res).clone().map_err(Error::from));
+            let pending = PendingRoute::new(pending).set_type(ty).start();
+            for path in self.paths.iter() {
+                app.add_path(path, PathRoute::Pending(pending.clone()), ty);
+            }
+            let paths = self.paths.clone();
+            Arbiter::spawn(ready.clone().map(move |res| {
+                if let Ok(ref upstream) = *res {
+                    let addr = upstream.clone().start();
+                    crate::app::APP.with(|app| {
+                        for path in paths.iter() {
+                            app.borrow_mut().add_path(path, PathRoute::Upstream(addr.clone()), ty);
+                        }
+                    });
+                }
+            }).map_err(|_| ()));
+        }
+
+        if self.reload {
+            app.keep_plugin(reload::watch(self, sockets, host, instance));
+        } else {
+            app.keep_plugin(instance);
+        }
         app
     }
-}
\ No newline at end of file
+
+    /// Start the plugin process, listening on `socket`.
+    pub(crate) fn spawn(&self, host: &Path, socket: PathBuf) -> io::Result<Instance> {
+        let mut cmd = Command::new(&self.exec_path);
+        self.sandbox.apply(&mut cmd);
+        // The host's activated sockets aren't the plugin's
+        for key in crate::activation::LISTEN_VARS.iter() {
+            cmd.env_remove(key);
+        }
+        cmd.env(output::LOG_ENV, output::host_filter())
+           .env(super::PROTOCOL_ENV, self.protocol.to_string())
+           .env(stdio::TRANSPORT_ENV, self.transport.as_str())
+           .env(super::ENCODING_ENV, self.encoding.as_str());
+        if self.transport == Transport::Stdio {
+            cmd.stdin(Stdio::piped());
+        }
+        let mut child = cmd.arg(host.to_str().unwrap_or("/dev/null"))
+                      .arg(socket.to_str().unwrap_or("/dev/null"))
+                      .args(&self.opt_args)
+                      .stdout(Stdio::piped())
+                      .stderr(Stdio::piped())
+                      .spawn()?;
+        if let Some(stderr) = child.stderr.take() {
+            output::forward(&self.name, stderr, Level::Warn);
+        }
+        let upstream = match (self.transport, child.stdin.take(), child.stdout.take()) {
+            (Transport::Stdio, Some(stdin), Some(stdout)) => {
+                Remote::Stdio(stdio::StdioClient::new(&self.name, stdin, stdout, crate::http::DEFAULT_LIMIT)).into()
+            },
+            (_, _, stdout) => {
+                if let Some(stdout) = stdout {
+                    output::forward(&self.name, stdout, Level::Info);
+                }
+                Remote::LocalHttp(socket.clone()).with_config(ClientConfig { encoding: self.encoding, ..Default::default() })
+            },
+        };
+        Ok(Instance { child, upstream, socket })
+    }
+
+    /// Handshake with a freshly started plugin, resolving to `upstream`
+    /// once the plugin is ready and compatible.
+    pub(crate) fn connect(&self, upstream: Upstream) -> impl Future<Item=Upstream, Error=handshake::PluginError> {
+        let (name, version, protocol) = (self.name.clone(), self.version.clone(), self.protocol);
+        handshake::connect(upstream.clone(), self.handshake_timeout).then(move |res| {
+            let res = res
+                .map_err(|err| handshake::PluginError::Handshake { name: name.clone(), cause: err.to_string() })
+                .and_then(|resp| handshake::verify(&name, &version, protocol, &resp))
+                .map(|_| upstream);
+            if let Err(ref err) = res {
+                log::error!("Refusing to route to plugin: {}", err);
+            }
+            res
+        })
+    }
+
+    /// Route all of the plugin's messages to `upstream`, replacing any previous routes.
+    pub(crate) fn install(&self, app: &mut crate::App, upstream: &Upstream) {
+        let addr = upstream.clone().start();
+        for msg in self.messages.iter() {
+            (msg.as_str(), addr.clone()).route(app, self.ty);
+        }
+        for path in self.paths.iter() {
+            app.add_path(path, PathRoute::Upstream(addr.clone()), self.ty);
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Version negotiation between the host and a plugin.
+//!
+//! Once a plugin process is started, the host repeatedly sends a `Handshake`
+//! to the plugin until it is listening and answers. The plugin replies with its
+//! own version and protocol version, which are checked against the manifest
+//! before any messages are routed to it.
+
+use ::actix::dev::*;
+use failure::{Error, Fail};
+use futures::{future, future::Either, future::Loop, Future};
+use serde::{Deserialize, Serialize};
+use tokio::timer::{Delay, Timeout};
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::MessageExt;
+use crate::router::Upstream;
+use super::PROTOCOL_VERSION;
+
+/// Delay between handshake attempts.
+const RETRY: Duration = Duration::from_millis(20);
+
+/// Sent by the host to a newly started plugin.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Handshake {
+    /// Protocol version spoken by the host.
+    pub protocol: u32,
+}
+
+impl Message for Handshake {
+    type Result = HandshakeResponse;
+}
+
+impl MessageExt for Handshake {
+    const PATH: &'static str = "_plugin/handshake";
+
+    type Response = HandshakeResponse;
+}
+
+/// The plugin's reply to a `Handshake`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HandshakeResponse {
+    /// Version of the plugin itself.
+    pub version: String,
+    /// Protocol version the plugin was built against.
+    pub protocol: u32,
+}
+
+/// Reasons for the host to refuse routing to a plugin.
+#[derive(Clone, Debug, Fail)]
+pub enum PluginError {
+    #[fail(display = "plugin {} speaks protocol version {}, but the host requires {}", name, found, expected)]
+    Protocol { name: String, found: u32, expected: u32 },
+    #[fail(display = "plugin {} has version {}, which does not satisfy the requirement {}", name, found, required)]
+    Version { name: String, found: String, required: String },
+    #[fail(display = "plugin {} did not complete the handshake: {}", name, cause)]
+    Handshake { name: String, cause: String },
+}
+
+/// Responds to the host's `Handshake` on behalf of the plugin.
+pub(crate) struct HandshakeHandler {
+    pub version: String,
+}
+
+impl Actor for HandshakeHandler {
+    type Context = Context<Self>;
+}
+
+impl Handler<Handshake> for HandshakeHandler {
+    type Result = MessageResult<Handshake>;
+
+    fn handle(&mut self, msg: Handshake, _ctxt: &mut Context<Self>) -> Self::Result {
+        log::debug!("Handshake from host speaking protocol version {}", msg.protocol);
+        MessageResult(HandshakeResponse {
+            version: self.version.clone(),
+            protocol: PROTOCOL_VERSION,
+        })
+    }
+}
+
+/// Check the plugin's reply against what the manifest requires.
+pub(crate) fn verify(name: &str, required: &str, protocol: u32, resp: &HandshakeResponse) -> Result<(), PluginError> {
+    // Both the manifest and the running plugin must agree with the host
+    for &found in &[protocol, resp.protocol] {
+        if found != PROTOCOL_VERSION {
+            return Err(PluginError::Protocol {
+                name: name.to_string(),
+                found,
+                expected: PROTOCOL_VERSION,
+            });
+        }
+    }
+    let version_err = || PluginError::Version {
+        name: name.to_string(),
+        found: resp.version.clone(),
+        required: required.to_string(),
+    };
+    let req = semver::VersionReq::parse(required).map_err(|_| version_err())?;
+    let version = semver::Version::parse(&resp.version).map_err(|_| version_err())?;
+    if req.matches(&version) {
+        Ok(())
+    } else {
+        Err(version_err())
+    }
+}
+
+/// Whether `err` means the plugin isn't listening yet: its socket is missing,
+/// or nothing accepts connections on it.
+fn not_listening(err: &Error) -> bool {
+    match err.downcast_ref::<io::Error>() {
+        Some(err) => matches!(err.kind(), io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused),
+        None => false,
+    }
+}
+
+/// Send a `Handshake` to the plugin at `remote`, retrying while the plugin
+/// is still starting up, for up to `timeout` in all. Any other failure,
+/// such as an error reply, ends the handshake straight away.
+pub(crate) fn connect(remote: Upstream, timeout: Duration) -> impl Future<Item=HandshakeResponse, Error=Error> {
+    let attempts = future::loop_fn((), move |()| {
+        remote.send(&Handshake { protocol: PROTOCOL_VERSION }).0.then(move |res| {
+            match res {
+                Ok(resp) => Either::A(future::ok(Loop::Break(resp))),
+                Err(ref err) if not_listening(err) => {
+                    log::trace!("Plugin not ready yet: {}", err);
+                    Either::B(Delay::new(Instant::now() + RETRY)
+                        .map_err(Error::from)
+                        .map(|_| Loop::Continue(())))
+                },
+                Err(err) => Either::A(future::err(err)),
+            }
+        })
+    });
+    Timeout::new(attempts, timeout).map_err(move |err| {
+        err.into_inner().unwrap_or_else(|| failure::err_msg(format!("no reply within {:?}", timeout)))
+    })
+}
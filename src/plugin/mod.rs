@@ -1,14 +1,83 @@
+//! Run parts of an application as separate plugin processes.
+//!
+//! Plugins written in Rust can simply call `plugin::run` with an `App`,
+//! or `plugin::run_with_version` to report their own version.
+//! Plugins in any other language need to implement the protocol below.
+//!
+//! # Protocol (version 1)
+//!
+//! **Arguments.** The plugin executable is started as
+//! `<exec_path> <host socket> <plugin socket> [opt_args...]`. The plugin is expected
+//! to listen on the plugin socket. The host socket is dedicated to the plugin, and
+//! accepts messages from the plugin in the same format as below, as long as they are
+//! listed in the plugin's `allow` list. Anything else is rejected with `403 Forbidden`.
+//! Socket paths starting with `@` are in the Linux abstract namespace (see `crate::socket`).
+//! A plugin started through systemd socket activation listens on the inherited socket instead.
+//!
+//! **Environment.**
+//!
+//! - `ACTIX_DIRECTORY_PROTOCOL`: the protocol version spoken by the host.
+//! - `ACTIX_DIRECTORY_TRANSPORT`: `socket` (HTTP over the plugin socket) or `stdio`.
+//! - `ACTIX_DIRECTORY_ENCODING`: `cbor` or `json`, the encoding the host will send.
+//! - `ACTIX_DIRECTORY_LOG`: the host's log filter, in `RUST_LOG` syntax.
+//!
+//! **Messages.** Each message is an HTTP `POST` to `/<PATH>`, where `PATH` is the
+//! message's `MessageExt::PATH`. The body is encoded as per its `Content-Type`
+//! (`application/cbor` or `application/json`), and the reply must use the same encoding.
+//! String-typed messages are sent to `/` as an `OpaqueMessage`, i.e.
+//! `{"id": "<message id>", "inner": [<bytes>]}`, and are answered with another `OpaqueMessage`.
+//!
+//! **Handshake.** Before routing anything to the plugin, the host sends
+//! `{"protocol": 1}` to `/_plugin/handshake`, retrying until the plugin is listening,
+//! for up to `Plugin::handshake_timeout`.
+//! The plugin replies with `{"version": "<semver>", "protocol": 1}`; the host refuses
+//! to use plugins whose version doesn't match the manifest, or which speak another protocol.
+//!
+//! **Errors.** A plugin failing to handle a message replies with a non-2xx status
+//! and the body `{"error": "<description>"}` (see `http::ErrorEnvelope`).
+//!
+//! **Stdio transport.** With `ACTIX_DIRECTORY_TRANSPORT=stdio` nothing is served on
+//! the plugin socket. Instead, requests arrive on stdin as CBOR maps
+//! `{"id", "path", "body", "error"}` prefixed with their length as a 4-byte big-endian
+//! integer, and replies with the same `id` are written to stdout in the same way.
+//! The plugin should exit once its stdin is closed.
+//!
+//! Anything a plugin writes to stderr (or stdout, for the socket transport) is
+//! forwarded to the host's logger.
+
+mod callback;
 mod client;
+mod handshake;
+pub(crate) mod output;
+mod reload;
+mod sandbox;
 mod server;
+mod stdio;
 
-pub use self::server::run;
+pub use self::callback::{AccessDenied, Acl};
+pub use self::handshake::{Handshake, HandshakeResponse, PluginError};
+pub use self::output::LOG_ENV;
+pub use self::sandbox::{Limits, Sandbox};
+pub use self::server::{run, run_with_version};
+pub use self::stdio::{StdioClient, Transport, TRANSPORT_ENV};
 
 use serde::{Deserialize, Serialize};
 
 use std::path::PathBuf;
+use std::time::Duration;
 
 pub type Message = String;
 
+/// Version of the protocol spoken between the host and its plugins.
+/// Bumped whenever the handshake or transport changes incompatibly.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Environment variable carrying the protocol version the host expects.
+pub const PROTOCOL_ENV: &str = "ACTIX_DIRECTORY_PROTOCOL";
+
+/// Environment variable carrying the body encoding the host will use.
+pub const ENCODING_ENV: &str = "ACTIX_DIRECTORY_ENCODING";
+
 // #[derive(Clone, Debug, Deserialize, Serialize)]
 // pub struct Message {
 //     pub name: String,
@@ -19,15 +88,54 @@ pub type Message = String;
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Plugin {
     pub name: String,
-    // pub version: String,
+    /// Semver requirement the version reported by the plugin must satisfy,
+    /// e.g. `"^0.1"`.
+    #[serde(default = "any_version")]
+    pub version: String,
+    /// Protocol version the plugin was built against.
+    #[serde(default = "protocol_version")]
+    pub protocol: u32,
     /// path to plugin executable
     pub exec_path: PathBuf,
     #[serde(default)]
     pub opt_args: Vec<String>,
     #[serde(default)]
     pub messages: Vec<Message>,
+    /// The `MessageExt::PATH`s of typed messages served by the plugin.
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// How the host talks to the plugin.
+    #[serde(default)]
+    pub transport: Transport,
+    /// How message bodies sent to the plugin are encoded.
+    /// Only applies to the `Socket` transport; stdio frames are always CBOR.
+    #[serde(default)]
+    pub encoding: crate::http::Encoding,
+    /// Isolation and resource limits for the plugin process.
+    #[serde(default)]
+    pub sandbox: Sandbox,
+    /// Messages the plugin may send back to the host.
+    #[serde(default)]
+    pub allow: Acl,
+    /// Restart the plugin whenever `exec_path` changes on disk. Meant for development.
+    #[serde(default)]
+    pub reload: bool,
+    /// How long the plugin has to start listening and answer the handshake.
+    #[serde(default = "handshake_timeout")]
+    pub handshake_timeout: Duration,
 
     /// Is this a client/server/upstream plugin?
     pub ty: crate::RouteType,
 }
 
+fn any_version() -> String {
+    "*".to_string()
+}
+
+fn protocol_version() -> u32 {
+    PROTOCOL_VERSION
+}
+
+fn handshake_timeout() -> Duration {
+    Duration::from_secs(10)
+}
@@ -0,0 +1,63 @@
+//! Forward the output of plugin processes to the host's logger.
+
+use log::Level;
+
+use std::io::{BufRead, BufReader, Read};
+use std::thread;
+
+/// Environment variable used to pass the host's log filter down to a plugin,
+/// using the same syntax as `RUST_LOG`.
+pub const LOG_ENV: &str = "ACTIX_DIRECTORY_LOG";
+
+/// The filter the host is currently logging with.
+pub(crate) fn host_filter() -> String {
+    std::env::var("RUST_LOG").unwrap_or_else(|_| log::max_level().to_string().to_lowercase())
+}
+
+/// Read lines from `output` on a background thread and re-emit them
+/// through `log`, prefixed with the plugin's name.
+///
+/// Lines which don't carry a level of their own are logged at `default`.
+pub(crate) fn forward<R>(name: &str, output: R, default: Level)
+    where R: 'static + Read + Send
+{
+    let name = name.to_string();
+    let res = thread::Builder::new()
+        .name(format!("plugin-{}", name))
+        .spawn(move || {
+            // Continuation lines of a multi-line message stay at the level
+            // of the last line with a header.
+            let mut level = default;
+            for line in BufReader::new(output).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                match parse(&line) {
+                    Some((lvl, target, msg)) => {
+                        level = lvl;
+                        log::log!(level, "[{}] {}: {}", name, target, msg);
+                    },
+                    None => log::log!(level, "[{}] {}", name, line),
+                }
+            }
+            log::trace!("Output of plugin {} closed", name);
+        });
+    if let Err(err) = res {
+        log::error!("Failed to forward plugin output: {}", err);
+    }
+}
+
+/// Lines written by `env_logger` look like `[<timestamp> <LEVEL> <target>] <message>`,
+/// in which case the plugin's own level and target are kept.
+pub(crate) fn parse(line: &str) -> Option<(Level, &str, &str)> {
+    if !line.starts_with('[') {
+        return None;
+    }
+    let end = line.find(']')?;
+    let mut header = line[1..end].split_whitespace();
+    let _timestamp = header.next()?;
+    let level = header.next()?.parse().ok()?;
+    let target = header.next().unwrap_or("");
+    Some((level, target, line[end + 1..].trim_start()))
+}
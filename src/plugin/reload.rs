@@ -0,0 +1,145 @@
+//! Restarting plugins when their executable changes.
+//!
+//! The new process is started next to the old one, and only takes over the
+//! plugin's routes once it has passed the handshake. The old process keeps
+//! serving the requests it already received, and is stopped once they are done.
+//!
+//! Watching stops once the `App` the plugin was added to is dropped, and the
+//! plugin's processes are stopped with it.
+
+use ::actix::prelude::*;
+use log::*;
+
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::client::Instance;
+use super::Plugin;
+
+/// How often the executable is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long a replaced process may take to finish its requests before it is killed.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn modified(plugin: &Plugin) -> Option<SystemTime> {
+    std::fs::metadata(&plugin.exec_path).and_then(|meta| meta.modified()).ok()
+}
+
+struct Reloader {
+    plugin: Plugin,
+    sockets: crate::SocketDir,
+    /// The plugin's callback socket, shared by all of its processes.
+    host: PathBuf,
+    current: Instance,
+    modified: Option<SystemTime>,
+    /// Number of processes started so far, used to give each its own socket.
+    generation: usize,
+    reloading: bool,
+    /// Replaced processes, and when to give up waiting for them.
+    draining: Vec<(Instance, Instant)>,
+}
+
+impl Actor for Reloader {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctxt: &mut Context<Self>) {
+        ctxt.run_interval(WATCH_INTERVAL, |act, ctxt| {
+            act.drain();
+            act.check(ctxt);
+        });
+    }
+}
+
+impl Reloader {
+    /// Stop the replaced processes which are done.
+    fn drain(&mut self) {
+        let now = Instant::now();
+        let (done, draining) = self.draining.drain(..).partition(|(old, deadline)| {
+            old.upstream.in_flight() == 0 || *deadline <= now
+        });
+        self.draining = draining;
+        for (old, _) in done {
+            debug!("Stopping replaced process of plugin {}", self.plugin.name);
+            drop(old);
+        }
+    }
+
+    /// Start a new process if the executable has changed since the last one.
+    fn check(&mut self, ctxt: &mut Context<Self>) {
+        let modified = modified(&self.plugin);
+        if self.reloading || modified.is_none() || modified == self.modified {
+            return;
+        }
+        info!("Executable of plugin {} changed, reloading", self.plugin.name);
+        self.modified = modified;
+        self.generation += 1;
+        let socket = match self.sockets.path(&format!("{}.{}", self.plugin.name, self.generation)) {
+            Ok(socket) => socket,
+            Err(err) => {
+                error!("Failed to restart plugin {}: {}", self.plugin.name, err);
+                return;
+            },
+        };
+        self.sockets.remove_on_drop(&socket);
+        let new = match self.plugin.spawn(&self.host, socket) {
+            Ok(new) => new,
+            Err(err) => {
+                error!("Failed to restart plugin {}: {}", self.plugin.name, err);
+                return;
+            },
+        };
+        self.reloading = true;
+        let ready = self.plugin.connect(new.upstream.clone());
+        ctxt.spawn(fut::wrap_future(ready).then(move |res, act: &mut Self, _ctxt| {
+            act.reloading = false;
+            match res {
+                Ok(upstream) => {
+                    crate::app::APP.with(|app| act.plugin.install(&mut app.borrow_mut(), &upstream));
+                    let old = std::mem::replace(&mut act.current, new);
+                    act.draining.push((old, Instant::now() + DRAIN_TIMEOUT));
+                    info!("Plugin {} reloaded", act.plugin.name);
+                },
+                // Keep the old process, the executable may change again
+                Err(_) => drop(new),
+            }
+            fut::ok(())
+        }));
+    }
+}
+
+/// Stops the `Reloader`, which drops its processes along with it.
+struct Stop;
+
+impl Message for Stop {
+    type Result = ();
+}
+
+impl Handler<Stop> for Reloader {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Stop, ctxt: &mut Context<Self>) {
+        debug!("Stopping plugin {}", self.plugin.name);
+        ctxt.stop();
+    }
+}
+
+/// Restarts a plugin whenever its executable changes, until dropped.
+pub(crate) struct Watch(Addr<Reloader>);
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.0.do_send(Stop);
+    }
+}
+
+/// Restart `plugin` whenever its executable changes, starting from the `current` process.
+pub(crate) fn watch(plugin: Plugin, sockets: crate::SocketDir, host: PathBuf, current: Instance) -> Watch {
+    let modified = modified(&plugin);
+    Watch(Reloader {
+        plugin, sockets, host, current, modified,
+        generation: 0,
+        reloading: false,
+        draining: Vec::new(),
+    }.start())
+}
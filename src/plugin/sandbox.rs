@@ -0,0 +1,108 @@
+//! Restrictions applied to plugin processes before they start.
+
+use serde::{Deserialize, Serialize};
+
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Resource limits for a plugin process, applied with `setrlimit`.
+/// Unset limits are inherited from the host.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Limits {
+    /// Maximum CPU time, in seconds.
+    #[serde(default)]
+    pub cpu_seconds: Option<u64>,
+    /// Maximum size of the virtual address space, in bytes.
+    #[serde(default)]
+    pub address_space: Option<u64>,
+    /// Maximum number of open file descriptors.
+    #[serde(default)]
+    pub open_files: Option<u64>,
+}
+
+/// Isolation options for a plugin process.
+///
+/// By default a plugin inherits everything from the host, as any child process would.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Sandbox {
+    /// Start the plugin with an empty environment, except for the variables
+    /// listed in `env_allow` and those required by the plugin protocol.
+    #[serde(default)]
+    pub clear_env: bool,
+    /// Variables copied from the host's environment when `clear_env` is set.
+    #[serde(default)]
+    pub env_allow: Vec<String>,
+    /// Working directory of the plugin.
+    #[serde(default)]
+    pub working_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub limits: Limits,
+    /// Niceness of the plugin process, from -20 (highest priority) to 19.
+    #[serde(default)]
+    pub nice: Option<i32>,
+    /// User to run the plugin as. Requires the host to have permission to switch users.
+    #[serde(default)]
+    pub uid: Option<u32>,
+    /// Group to run the plugin as.
+    #[serde(default)]
+    pub gid: Option<u32>,
+}
+
+impl Sandbox {
+    /// Configure `cmd` to start the plugin inside this sandbox.
+    pub(crate) fn apply(&self, cmd: &mut Command) {
+        if self.clear_env {
+            cmd.env_clear();
+            for key in &self.env_allow {
+                if let Some(val) = std::env::var_os(key) {
+                    cmd.env(key, val);
+                }
+            }
+        }
+        if let Some(ref dir) = self.working_dir {
+            cmd.current_dir(dir);
+        }
+        if let Some(gid) = self.gid {
+            cmd.gid(gid);
+        }
+        if let Some(uid) = self.uid {
+            cmd.uid(uid);
+        }
+
+        let limits = [
+            (libc::RLIMIT_CPU, self.limits.cpu_seconds),
+            (libc::RLIMIT_AS, self.limits.address_space),
+            (libc::RLIMIT_NOFILE, self.limits.open_files),
+        ];
+        let nice = self.nice;
+        if nice.is_none() && limits.iter().all(|(_, l)| l.is_none()) {
+            return;
+        }
+        // Runs in the child between `fork` and `exec`, so only
+        // async-signal-safe calls are allowed here.
+        let pre_exec = move || {
+            for &(resource, limit) in limits.iter() {
+                if let Some(limit) = limit {
+                    let rlim = libc::rlimit {
+                        rlim_cur: limit as libc::rlim_t,
+                        rlim_max: limit as libc::rlim_t,
+                    };
+                    if unsafe { libc::setrlimit(resource, &rlim) } != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+            }
+            if let Some(nice) = nice {
+                if unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        };
+        unsafe {
+            cmd.pre_exec(pre_exec);
+        }
+    }
+}
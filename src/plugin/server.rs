@@ -3,7 +3,15 @@ use actix::dev::*;
 use std::env;
 use std::path::PathBuf;
 
+/// Run `app` as a plugin. It reports version 0.0.0 to the host during
+/// the handshake, which only satisfies manifests without a version requirement.
 pub fn run(app: crate::App) {
+    run_with_version(app, "0.0.0")
+}
+
+/// Run `app` as a plugin, reporting `version` to the host during the handshake.
+/// Usually called as `plugin::run_with_version(app, env!("CARGO_PKG_VERSION"))`.
+pub fn run_with_version(app: crate::App, version: &str) {
 	init_logger();
 	log::info!("Starting plugin");
 	let mut args = env::args();
@@ -20,14 +28,30 @@ pub fn run(app: crate::App) {
 	log::info!("Plugin: listening on socket {:?}, server at: {:?}", sin, sout);
 	// let mut socket: Option<String> = None;
     let sys = System::new("test_server");
-   	let _sock = app.serve_local_http(Some(sin)).clone();
+    let handshake = super::handshake::HandshakeHandler { version: version.to_string() }.start();
+    // Anything the plugin can't handle itself is sent back to the host
+    let app = app.route::<super::Handshake, _>(handshake, crate::RouteType::Server)
+                 .default_route(sout);
+    let transport = super::Transport::from_env();
+    match transport {
+        super::Transport::Socket => {
+            if let Err(err) = app.serve_local_http_activated(Some(sin)) {
+                log::error!("Plugin: {}", err);
+                std::process::exit(-1i32);
+            }
+        },
+        super::Transport::Stdio => super::stdio::serve(),
+    }
     app.make_current();
     sys.run();
 }
 
+/// Log with the filter passed down by the host, if any.
+/// The output is collected by the host and re-emitted through its own logger.
 pub fn init_logger() {
-	if std::env::var("TEST_LOG").is_ok() {
-	    ::std::env::set_var("RUST_LOG", format!("debug,actix_web={1},actix={1},actix_directory={0}", "trace", "trace"));
-	    env_logger::init();
+	if let Ok(filter) = std::env::var(super::output::LOG_ENV) {
+	    env_logger::Builder::new()
+	        .parse_filters(&filter)
+	        .init();
 	}
-}
\ No newline at end of file
+}
\ No newline at end of file
@@ -0,0 +1,244 @@
+//! Transport for plugins which talk to the host over their stdin/stdout,
+//! rather than a filesystem socket.
+//!
+//! Each message is a CBOR encoded `Frame`, prefixed by its length as a
+//! 4-byte big-endian integer. Requests and responses are matched by `id`,
+//! so several requests can be in flight at once.
+
+use ::actix::prelude::*;
+use bytes::Bytes;
+use failure::Error;
+use futures::{future, sync::{mpsc, oneshot}, Future, Sink, Stream};
+use log::*;
+use serde::{Deserialize, Serialize};
+use tokio::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::process::{ChildStdin, ChildStdout};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::{app, deserialize, serialize, MessageExt};
+
+/// Environment variable telling a plugin which transport the host expects.
+pub const TRANSPORT_ENV: &str = "ACTIX_DIRECTORY_TRANSPORT";
+
+/// How a plugin is reached by the host.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// HTTP over a Unix socket created by the plugin.
+    #[default]
+    Socket,
+    /// Length-prefixed CBOR frames over the plugin's stdin/stdout.
+    Stdio,
+}
+
+impl Transport {
+    /// The transport requested by the host, when running as a plugin.
+    pub fn from_env() -> Self {
+        match std::env::var(TRANSPORT_ENV).as_ref().map(String::as_str) {
+            Ok("stdio") => Transport::Stdio,
+            _ => Transport::Socket,
+        }
+    }
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Transport::Socket => "socket",
+            Transport::Stdio => "stdio",
+        }
+    }
+}
+
+/// A single request or response.
+#[derive(Debug, Deserialize, Serialize)]
+struct Frame {
+    id: usize,
+    path: String,
+    #[serde(with = "serde_bytes")]
+    body: Vec<u8>,
+    /// Set instead of `body` when the plugin failed to handle the request.
+    #[serde(default)]
+    error: Option<String>,
+}
+
+/// The requests waiting for a reply, and the way to send more.
+struct Channel {
+    pending: HashMap<usize, oneshot::Sender<Result<Vec<u8>, String>>>,
+    /// Taken once the plugin can't reply anymore, which closes its stdin.
+    writer: Option<mpsc::UnboundedSender<Vec<u8>>>,
+}
+
+/// The host's end of the stdio transport to a single plugin.
+/// Frames from the plugin larger than the limit close the transport.
+#[derive(Clone)]
+pub struct StdioClient {
+    name: String,
+    next_id: Arc<AtomicUsize>,
+    channel: Arc<Mutex<Channel>>,
+}
+
+impl std::fmt::Debug for StdioClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "StdioClient({})", self.name)
+    }
+}
+
+impl StdioClient {
+    /// Take over the plugin's stdin/stdout, reading frames of at most `limit` bytes.
+    /// Frames are written and read on background threads, since the pipes are blocking.
+    pub(crate) fn new(name: &str, stdin: ChildStdin, stdout: ChildStdout, limit: usize) -> Self {
+        let (writer, rx) = mpsc::unbounded::<Vec<u8>>();
+        let channel = Arc::new(Mutex::new(Channel { pending: HashMap::new(), writer: Some(writer) }));
+
+        let mut stdin = stdin;
+        let name2 = name.to_string();
+        let res = thread::Builder::new().name(format!("plugin-{}-stdin", name)).spawn(move || {
+            for frame in rx.wait().filter_map(Result::ok) {
+                let len = (frame.len() as u32).to_be_bytes();
+                if let Err(err) = stdin.write_all(&len).and_then(|_| stdin.write_all(&frame)) {
+                    error!("Failed to write to plugin {}: {}", name2, err);
+                    break;
+                }
+            }
+        });
+        if let Err(err) = res {
+            error!("Failed to start plugin writer: {}", err);
+        }
+
+        let mut stdout = stdout;
+        let name2 = name.to_string();
+        let channel2 = channel.clone();
+        let res = thread::Builder::new().name(format!("plugin-{}-stdout", name)).spawn(move || {
+            loop {
+                let frame = match read_frame(&mut stdout, limit) {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => break,
+                    Err(err) => {
+                        error!("Failed to read from plugin {}: {}", name2, err);
+                        break;
+                    },
+                };
+                match deserialize::<Frame>(&frame) {
+                    Ok(frame) => {
+                        let res = match frame.error {
+                            Some(err) => Err(err),
+                            None => Ok(frame.body),
+                        };
+                        if let Some(tx) = channel2.lock().unwrap().pending.remove(&frame.id) {
+                            let _ = tx.send(res);
+                        }
+                    },
+                    Err(err) => error!("Invalid frame from plugin {}: {}", name2, err),
+                }
+            }
+            debug!("Plugin {} stopped replying", name2);
+            // Fail anything still waiting on a reply, or sent later,
+            // and close stdin so the plugin stops too
+            let mut channel = channel2.lock().unwrap();
+            channel.pending.clear();
+            channel.writer = None;
+        });
+        if let Err(err) = res {
+            error!("Failed to start plugin reader: {}", err);
+        }
+
+        StdioClient {
+            name: name.to_string(),
+            next_id: Arc::default(),
+            channel,
+        }
+    }
+
+    /// Send `msg` to the plugin and wait for the reply.
+    pub fn send<M>(&self, msg: &M) -> impl Future<Item=M::Response, Error=Error>
+        where M: MessageExt
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        let frame = serialize(msg).and_then(|body| serialize(&Frame {
+            id,
+            path: M::PATH.to_string(),
+            body,
+            error: None,
+        }));
+        trace!("Sending frame {} to plugin {} on path {:?}", id, self.name, M::PATH);
+        let sent = frame.and_then(|frame| {
+            let not_running = || failure::err_msg(format!("plugin {} is not running", self.name));
+            let mut channel = self.channel.lock().unwrap();
+            match &channel.writer {
+                Some(writer) => writer.unbounded_send(frame).map_err(|_| not_running())?,
+                None => return Err(not_running()),
+            }
+            channel.pending.insert(id, tx);
+            Ok(())
+        });
+        future::result(sent)
+            .and_then(|_| rx.map_err(Error::from))
+            .and_then(|res| res.map_err(failure::err_msg))
+            .and_then(|body| deserialize(&body))
+    }
+}
+
+/// Read a single length-prefixed frame of at most `limit` bytes,
+/// or `None` once the stream is closed.
+fn read_frame<R: Read>(r: &mut R, limit: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match r.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > limit {
+        let msg = format!("frame of {} bytes is larger than the limit of {}", len, limit);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+    let mut frame = vec![0u8; len];
+    r.read_exact(&mut frame)?;
+    Ok(Some(frame))
+}
+
+/// The plugin's end of the transport: handle frames arriving on stdin using
+/// the current `App`, and write the replies to stdout.
+///
+/// The plugin stops once the host closes its stdin.
+pub(crate) fn serve() {
+    let (tx, rx) = mpsc::unbounded::<Vec<u8>>();
+    let writer = FramedWrite::new(tokio_stdin_stdout::stdout(0), LengthDelimitedCodec::new());
+    Arbiter::spawn(
+        writer.send_all(rx.map(Bytes::from).map_err(|_| io::Error::new(io::ErrorKind::Other, "reply channel closed")))
+              .map(|_| ())
+              .map_err(|err| error!("Failed to write to stdout: {}", err))
+    );
+
+    let reader = FramedRead::new(tokio_stdin_stdout::stdin(0), LengthDelimitedCodec::new());
+    Arbiter::spawn(reader.map_err(Error::from).for_each(move |frame| {
+        let Frame { id, path, body, .. } = deserialize(&frame)?;
+        trace!("Received frame {} on path {:?}", id, path);
+        let tx = tx.clone();
+        let fut = app::APP.with(|app| app.borrow().dispatch_raw(&path, &body));
+        Arbiter::spawn(fut.then(move |res| {
+            let frame = match res {
+                Ok(body) => Frame { id, path, body, error: None },
+                Err(err) => Frame { id, path, body: Vec::new(), error: Some(err.to_string()) },
+            };
+            match serialize(&frame) {
+                Ok(frame) => { let _ = tx.unbounded_send(frame); },
+                Err(err) => error!("Failed to serialize reply: {}", err),
+            }
+            Ok(())
+        }));
+        Ok(())
+    }).then(|res| {
+        if let Err(err) = res {
+            error!("Failed to read from stdin: {}", err);
+        }
+        info!("Host closed stdin, stopping plugin");
+        System::current().stop();
+        Ok(())
+    }));
+}
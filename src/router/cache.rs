@@ -0,0 +1,204 @@
+//! Client routes which keep the responses to messages for a while.
+
+use ::actix::dev::*;
+use failure::{err_msg, Error};
+use futures::future;
+use log::*;
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::{FutActResponse, MessageExt};
+use super::{IntoMember, Member};
+
+/// What's kept of a response: failures are only kept by their description.
+type Outcome<R> = Result<R, String>;
+
+struct Entry<R> {
+    outcome: Outcome<R>,
+    expires: Instant,
+    /// When it was last used, as the key of `CachingClient::used`.
+    used: u64,
+}
+
+/// A client route which replies to messages with the same `MessageExt::key`
+/// with the response to the first of them, until it expires.
+///
+/// Start it to keep its address, which can `Invalidate` entries:
+///
+/// ```ignore
+/// let cache = CachingClient::<Lookup>::new(Remote::from(url)).ttl(Duration::from_secs(10)).start();
+/// let app = App::new().route::<Lookup, _>(cache.clone(), RouteType::Client);
+/// cache.do_send(Invalidate::all());
+/// ```
+pub struct CachingClient<M: MessageExt> {
+    route: Member<M>,
+    ttl: Duration,
+    negative_ttl: Option<Duration>,
+    max_entries: usize,
+    entries: HashMap<Vec<u8>, Entry<M::Response>>,
+    /// The keys of the entries, least recently used first.
+    used: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+    /// Bumped by each `Invalidate`, so responses to messages sent before it aren't kept.
+    generation: u64,
+}
+
+impl<M: MessageExt> Clone for CachingClient<M> {
+    /// A cache with the same route and settings, but none of the entries.
+    fn clone(&self) -> Self {
+        CachingClient {
+            route: self.route.clone(),
+            ttl: self.ttl,
+            negative_ttl: self.negative_ttl,
+            max_entries: self.max_entries,
+            entries: HashMap::new(),
+            used: BTreeMap::new(),
+            tick: 0,
+            generation: 0,
+        }
+    }
+}
+
+impl<M: MessageExt> CachingClient<M> {
+    /// Cache the responses of `route` for a minute, keeping up to 1024 of them.
+    pub fn new<R: IntoMember<M>>(route: R) -> Self {
+        CachingClient {
+            route: route.into_member(),
+            ttl: Duration::from_secs(60),
+            negative_ttl: None,
+            max_entries: 1024,
+            entries: HashMap::new(),
+            used: BTreeMap::new(),
+            tick: 0,
+            generation: 0,
+        }
+    }
+
+    /// How long responses are kept.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Also keep failures, for `ttl`. They are replied to with an error
+    /// describing the original one.
+    pub fn negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = Some(ttl);
+        self
+    }
+
+    /// Evict the least recently used entries beyond `max_entries`.
+    pub fn max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    fn touch(&mut self, key: &[u8]) {
+        self.tick += 1;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.used.remove(&entry.used);
+            entry.used = self.tick;
+            self.used.insert(self.tick, key.to_vec());
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.used.remove(&entry.used);
+        }
+    }
+
+    fn insert(&mut self, key: Vec<u8>, outcome: Outcome<M::Response>, ttl: Duration) {
+        self.remove(&key);
+        self.tick += 1;
+        self.used.insert(self.tick, key.clone());
+        self.entries.insert(key, Entry { outcome, expires: Instant::now() + ttl, used: self.tick });
+        while self.entries.len() > self.max_entries {
+            let oldest = match self.used.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            let key = self.used.remove(&oldest).unwrap();
+            self.entries.remove(&key);
+        }
+    }
+}
+
+impl<M: MessageExt> Actor for CachingClient<M> {
+    type Context = Context<Self>;
+}
+
+impl<M> Handler<M> for CachingClient<M>
+    where M: MessageExt,
+          M::Response: Clone,
+{
+    type Result = FutActResponse<Self, M>;
+
+    fn handle(&mut self, msg: M, _ctxt: &mut Context<Self>) -> Self::Result {
+        let key = match msg.key() {
+            Some(key) => key,
+            None => return FutActResponse::from(fut::wrap_future(self.route.send(msg))),
+        };
+        match self.entries.get(&key).map(|entry| entry.expires > Instant::now()) {
+            Some(true) => {
+                trace!("Replying to {:?} from the cache", msg);
+                self.touch(&key);
+                let outcome = self.entries[&key].outcome.clone().map_err(err_msg);
+                return FutActResponse::from(fut::wrap_future(future::result(outcome)));
+            },
+            Some(false) => self.remove(&key),
+            None => (),
+        }
+        let generation = self.generation;
+        FutActResponse::from(fut::wrap_future(self.route.send(msg)).then(move |res: Result<M::Response, Error>, act: &mut Self, _| {
+            if act.generation != generation {
+                trace!("Not keeping a response from before the cache was invalidated");
+                return fut::result(res);
+            }
+            match &res {
+                Ok(resp) => act.insert(key, Ok(resp.clone()), act.ttl),
+                Err(err) => if let Some(ttl) = act.negative_ttl {
+                    act.insert(key, Err(err.to_string()), ttl);
+                },
+            }
+            fut::result(res)
+        }))
+    }
+}
+
+/// Drop the entries of a `CachingClient<M>`, so the next messages go to its route.
+pub struct Invalidate<M: MessageExt>(Option<M>);
+
+impl<M: MessageExt> Invalidate<M> {
+    /// Drop every entry.
+    pub fn all() -> Self {
+        Invalidate(None)
+    }
+
+    /// Drop the entry for messages with the same key as `msg`.
+    pub fn message(msg: M) -> Self {
+        Invalidate(Some(msg))
+    }
+}
+
+impl<M: MessageExt> Message for Invalidate<M> {
+    type Result = ();
+}
+
+impl<M: MessageExt> Handler<Invalidate<M>> for CachingClient<M> {
+    type Result = ();
+
+    fn handle(&mut self, Invalidate(msg): Invalidate<M>, _ctxt: &mut Context<Self>) {
+        self.generation += 1;
+        match msg {
+            Some(msg) => if let Some(key) = msg.key() {
+                self.remove(&key);
+            },
+            None => {
+                self.entries.clear();
+                self.used.clear();
+            },
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Routes which keep messages in an `Outbox` until their upstream has them.
+
+use ::actix::dev::*;
+use failure::{err_msg, Error};
+use futures::{future::{self, Loop}, sync::oneshot, Future};
+use log::*;
+use tokio::timer::Delay;
+
+use std::marker::PhantomData;
+use std::time::Instant;
+
+use crate::{ForwardResponse, MessageExt, Outbox};
+use super::Upstream;
+
+/// A route which keeps each message in an `Outbox`, and sends it to an
+/// upstream until it's delivered, or the outbox gives up on it.
+///
+/// Senders waiting for the reply get it once the message is delivered,
+/// however many attempts that takes, or the last error once it's dead.
+/// Notifications only wait for the message to be kept. Messages left in the
+/// outbox by an earlier run are sent again once the route is started.
+///
+/// ```ignore
+/// let outbox = Outbox::open("orders.outbox")?;
+/// let app = App::new().route::<Order, _>(Durable::new(Remote::from(url), outbox), RouteType::Upstream);
+/// ```
+pub struct Durable<M: MessageExt> {
+    upstream: Upstream,
+    outbox: Outbox,
+    _msg: PhantomData<M>,
+}
+
+impl<M: MessageExt> Clone for Durable<M> {
+    fn clone(&self) -> Self {
+        Durable { upstream: self.upstream.clone(), outbox: self.outbox.clone(), _msg: PhantomData }
+    }
+}
+
+impl<M: MessageExt> Durable<M> {
+    pub fn new<U: Into<Upstream>>(upstream: U, outbox: Outbox) -> Self {
+        Durable { upstream: upstream.into(), outbox, _msg: PhantomData }
+    }
+
+    /// Keep `msg` in the outbox, and start delivering it.
+    fn queue(&self, msg: M) -> Result<oneshot::Receiver<Result<M::Response, Error>>, Error> {
+        let (id, key) = self.outbox.push(&msg)?;
+        let (tx, rx) = oneshot::channel();
+        // Spawned, so the message is delivered even if nothing waits for it
+        Arbiter::spawn(deliver(self.upstream.clone(), self.outbox.clone(), id, key, msg).then(move |res| {
+            let _ = tx.send(res);
+            Ok(())
+        }));
+        Ok(rx)
+    }
+}
+
+impl<M: MessageExt> Actor for Durable<M> {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctxt: &mut Context<Self>) {
+        for (id, key, body) in self.outbox.resume(M::PATH) {
+            match crate::deserialize::<M>(&body) {
+                Ok(msg) => {
+                    trace!("Resuming delivery of message {} from the outbox", id);
+                    Arbiter::spawn(deliver(self.upstream.clone(), self.outbox.clone(), id, key, msg).then(|_| Ok(())));
+                },
+                Err(err) => {
+                    self.outbox.failed(id, u32::MAX, &err);
+                },
+            }
+        }
+    }
+}
+
+impl<M: MessageExt> Handler<M> for Durable<M> {
+    type Result = ForwardResponse<M>;
+
+    fn handle(&mut self, msg: M, _ctxt: &mut Context<Self>) -> Self::Result {
+        let (route, notify) = (self.clone(), self.clone());
+        ForwardResponse::new(
+            msg,
+            move |msg| future::result(route.queue(msg))
+                .and_then(|rx| rx.map_err(|_| err_msg("outbox delivery was dropped")))
+                .and_then(|res| res),
+            move |msg| future::result(notify.queue(msg).map(|_| ())),
+        )
+    }
+}
+
+/// Send `msg` until `upstream` replies, or `outbox` gives up on it.
+fn deliver<M: MessageExt>(upstream: Upstream, outbox: Outbox, id: u64, key: String, msg: M) -> impl Future<Item=M::Response, Error=Error> {
+    future::loop_fn(1, move |attempts| {
+        let outbox = outbox.clone();
+        upstream.send_idempotent(&msg, key.clone()).0.then(move |res| match res {
+            Ok(resp) => {
+                outbox.delivered(id);
+                future::Either::A(future::ok(Loop::Break(resp)))
+            },
+            Err(err) => match outbox.failed(id, attempts, &err) {
+                Some(wait) => future::Either::B(Delay::new(Instant::now() + wait)
+                    .map_err(Error::from)
+                    .map(move |()| Loop::Continue(attempts + 1))),
+                None => future::Either::A(future::err(err)),
+            },
+        })
+    })
+}
@@ -0,0 +1,206 @@
+//! Named groups of handlers for the same message, such as the shards or
+//! replicas of a service, which are all sent each message.
+
+use ::actix::dev::*;
+use failure::{Error, Fail};
+use futures::{future, stream, Future, Stream};
+use log::*;
+use tokio::timer::Timeout;
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::MessageExt;
+use super::{RouterError, Upstream};
+
+/// Where a member sends its messages.
+enum Target<M: MessageExt> {
+    Local(Recipient<M>),
+    /// Sent without going through an actor, to get the remote's errors.
+    Remote(Box<Upstream>),
+}
+
+impl<M: MessageExt> Clone for Target<M> {
+    fn clone(&self) -> Self {
+        match self {
+            Target::Local(recipient) => Target::Local(recipient.clone()),
+            Target::Remote(upstream) => Target::Remote(upstream.clone()),
+        }
+    }
+}
+
+/// A handler in a route group or an event's subscribers,
+/// with a description for reporting its failures.
+pub struct Member<M: MessageExt> {
+    name: String,
+    target: Target<M>,
+}
+
+impl<M: MessageExt> Clone for Member<M> {
+    fn clone(&self) -> Self {
+        Member { name: self.name.clone(), target: self.target.clone() }
+    }
+}
+
+impl<M: MessageExt> Member<M> {
+    /// The handler's actor type, or its remote.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn send(&self, msg: M) -> Box<dyn Future<Item=M::Response, Error=Error>> {
+        match &self.target {
+            Target::Local(recipient) => Box::new(recipient.send(msg).map_err(Error::from)),
+            Target::Remote(upstream) => upstream.send(&msg).0,
+        }
+    }
+}
+
+/// Anything which can be a `Member`: `Recipient`s, `Addr`s
+/// and `Remote`s or `Upstream`s.
+pub trait IntoMember<M: MessageExt> {
+    fn into_member(self) -> Member<M>;
+}
+
+impl<M: MessageExt> IntoMember<M> for Recipient<M> {
+    fn into_member(self) -> Member<M> {
+        Member { name: "recipient".to_string(), target: Target::Local(self) }
+    }
+}
+
+impl<A, M> IntoMember<M> for Addr<A>
+    where M: MessageExt,
+          A: Actor<Context=Context<A>> + Handler<M>,
+{
+    fn into_member(self) -> Member<M> {
+        Member { name: std::any::type_name::<A>().to_string(), target: Target::Local(self.recipient()) }
+    }
+}
+
+impl<R, M> IntoMember<M> for R
+    where M: MessageExt,
+          R: Into<Upstream>,
+{
+    fn into_member(self) -> Member<M> {
+        let upstream = self.into();
+        Member { name: format!("{:?}", upstream.remote), target: Target::Remote(Box::new(upstream)) }
+    }
+}
+
+/// The route groups of an app for the message `M`, by name.
+pub(crate) struct Groups<M: MessageExt>(pub(crate) HashMap<String, Vec<Member<M>>>);
+
+/// Failures of a scatter-gather send as a whole, or of one of its members.
+#[derive(Debug, Fail)]
+pub enum ScatterError {
+    #[fail(display = "{} did not reply in time", member)]
+    Timeout { member: String },
+    #[fail(display = "only {} of the {} replies needed succeeded", got, needed)]
+    NotEnough { got: usize, needed: usize },
+}
+
+/// The reply of one member of a group.
+#[derive(Debug)]
+pub struct Reply<R> {
+    pub member: String,
+    pub result: Result<R, Error>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Mode {
+    All,
+    Partial,
+    First(usize),
+}
+
+/// A message to send to every member of a route group, built with `app::scatter`.
+///
+/// By default, every member must reply successfully.
+pub struct Scatter<M: MessageExt> {
+    group: String,
+    members: Option<Vec<Member<M>>>,
+    msg: M,
+    timeout: Option<Duration>,
+    mode: Mode,
+}
+
+impl<M: MessageExt + Clone> Scatter<M> {
+    pub(crate) fn new(group: &str, members: Option<Vec<Member<M>>>, msg: M) -> Self {
+        Scatter { group: group.to_string(), members, msg, timeout: None, mode: Mode::All }
+    }
+
+    /// Give up on members which haven't replied after `timeout`, as if they had failed.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Wait for every member, and return their failures along with the other replies.
+    pub fn partial(mut self) -> Self {
+        self.mode = Mode::Partial;
+        self
+    }
+
+    /// Only wait for the first `n` successful replies. Fails with
+    /// `ScatterError::NotEnough` once that many can't be had.
+    pub fn first(mut self, n: usize) -> Self {
+        self.mode = Mode::First(n);
+        self
+    }
+
+    /// Send the message, and get the replies: in the order members joined the
+    /// group, or in the order they arrived with `first`.
+    pub fn gather(self) -> impl Future<Item=Vec<Reply<M::Response>>, Error=Error> {
+        let Scatter { group, members, msg, timeout, mode } = self;
+        let members = match members {
+            Some(members) => members,
+            None => {
+                error!("No route group {:?} for {:?}", group, crate::get_type!(M));
+                return future::Either::A(future::err(RouterError::default().into()));
+            },
+        };
+        trace!("Scattering {:?} to {} members of {:?}", msg, members.len(), group);
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let sends = members.iter().map(|member| {
+            let name = member.name.clone();
+            let send = member.send(msg.clone());
+            match deadline {
+                Some(deadline) => future::Either::A(Timeout::new_at(send, deadline).map_err(move |err| {
+                    err.into_inner().unwrap_or_else(|| ScatterError::Timeout { member: name }.into())
+                })),
+                None => future::Either::B(send),
+            }.then({
+                let member = member.name.clone();
+                move |result| Ok::<_, Error>(Reply { member, result })
+            })
+        }).collect::<Vec<_>>();
+        future::Either::B(match mode {
+            Mode::All => future::Either::A(future::join_all(sends).and_then(|replies| {
+                match replies.iter().position(|reply| reply.result.is_err()) {
+                    Some(failed) => Err(replies.into_iter().nth(failed).unwrap().result.err().unwrap()),
+                    None => Ok(replies),
+                }
+            })),
+            Mode::Partial => future::Either::B(future::Either::A(future::join_all(sends))),
+            Mode::First(needed) => future::Either::B(future::Either::B(
+                stream::futures_unordered(sends)
+                    .filter(|reply| reply.result.is_ok())
+                    .take(needed as u64)
+                    .collect()
+                    .and_then(move |replies| match replies.len() {
+                        got if got < needed => Err(ScatterError::NotEnough { got, needed }.into()),
+                        _ => Ok(replies),
+                    })
+            )),
+        })
+    }
+
+    /// Send the message, and combine the successful replies with `f`.
+    pub fn reduce<T, F>(self, init: T, f: F) -> impl Future<Item=T, Error=Error>
+        where F: FnMut(T, M::Response) -> T
+    {
+        self.gather().map(move |replies| {
+            replies.into_iter().filter_map(|reply| reply.result.ok()).fold(init, f)
+        })
+    }
+}
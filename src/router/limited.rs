@@ -0,0 +1,46 @@
+//! Routes which only let so many messages through.
+
+use ::actix::dev::*;
+
+use crate::limit::{self, Limiter};
+use crate::{FutResponse, MessageExt};
+use super::{IntoMember, Member};
+
+/// A route which sends its messages within the limits of a `Limiter`.
+/// Messages beyond them wait, or fail with `Overloaded`.
+///
+/// Routes sharing the same `Limiter` share its limits.
+///
+/// ```ignore
+/// let limiter = Limiter::new().max_concurrent(8).queue(64);
+/// let app = App::new().route::<Lookup, _>(Limited::new(Remote::from(url), limiter), RouteType::Upstream);
+/// ```
+pub struct Limited<M: MessageExt> {
+    route: Member<M>,
+    limiter: Limiter,
+}
+
+impl<M: MessageExt> Clone for Limited<M> {
+    fn clone(&self) -> Self {
+        Limited { route: self.route.clone(), limiter: self.limiter.clone() }
+    }
+}
+
+impl<M: MessageExt> Limited<M> {
+    pub fn new<R: IntoMember<M>>(route: R, limiter: Limiter) -> Self {
+        Limited { route: route.into_member(), limiter }
+    }
+}
+
+impl<M: MessageExt> Actor for Limited<M> {
+    type Context = Context<Self>;
+}
+
+impl<M: MessageExt> Handler<M> for Limited<M> {
+    type Result = FutResponse<M>;
+
+    fn handle(&mut self, msg: M, _ctxt: &mut Context<Self>) -> Self::Result {
+        let route = self.route.clone();
+        FutResponse(limit::limited(Some(&self.limiter), futures::future::lazy(move || route.send(msg))))
+    }
+}
@@ -11,11 +11,42 @@ use std::collections::HashMap;
 
 use crate::{get_type, MessageExt, OpaqueMessage};
 
+mod cache;
+mod durable;
+mod group;
+mod limited;
 mod pending;
+mod single_flight;
 mod upstream;
 
+pub use self::cache::{CachingClient, Invalidate};
+pub use self::durable::Durable;
+pub use self::group::{IntoMember, Member, Reply, Scatter, ScatterError};
+pub use self::limited::Limited;
+pub(crate) use self::group::Groups;
 pub use self::pending::PendingRoute;
-pub use self::upstream::Remote;
+pub use self::single_flight::SingleFlight;
+pub use self::upstream::{Remote, Upstream};
+pub(crate) use self::upstream::InFlight;
+
+/// A route for every message type with a given `MessageExt::PATH`.
+///
+/// Used for handlers which only know messages by their path, such as
+/// plugins, so can't be added to the routing table by type.
+#[derive(Clone)]
+pub enum PathRoute {
+    Upstream(Addr<Upstream>),
+    Pending(Addr<PendingRoute<Upstream>>),
+}
+
+impl PathRoute {
+    fn recipient<M: MessageExt>(&self) -> Recipient<M> {
+        match self {
+            PathRoute::Upstream(addr) => addr.clone().recipient(),
+            PathRoute::Pending(addr) => addr.clone().recipient(),
+        }
+    }
+}
 
 /// A lookup from `Message` types to addresses to request handlers.
 /// This is encapsulated by an `AnyMap`, but the method `insert_handler`, 
@@ -25,7 +56,8 @@ pub struct Router {
     pub name: String,
     pub routes: anymap::AnyMap,
     pub str_routes: HashMap<String, Recipient<OpaqueMessage>>,
-    pub default: Option<Addr<Remote>>,
+    pub path_routes: HashMap<String, PathRoute>,
+    pub default: Option<Addr<Upstream>>,
     #[cfg(feature="debugging_info")]
     _info: Vec<String>,
 }
@@ -42,6 +74,7 @@ impl Router {
             name: format!("{} on thread: {:?}", name, std::thread::current().id()),
             routes: anymap::AnyMap::new(),
             str_routes: HashMap::new(),
+            path_routes: HashMap::new(),
             default: None,
             #[cfg(feature="debugging_info")]
             _info: Vec::new(),
@@ -68,6 +101,12 @@ impl Router {
         self._info.push(id.to_string());
     }
 
+    pub fn insert_path(&mut self, path: &str, handler: PathRoute) {
+        self.path_routes.insert(path.to_string(), handler);
+        #[cfg(feature="debugging_info")]
+        self._info.push(format!("/{}", path));
+    }
+
     /// Get the handler identified by the generic type parameter `M`.
     fn get_str(&self, id: &str) -> Option<Recipient<OpaqueMessage>>
     {
@@ -80,7 +119,9 @@ impl Router {
         where M: MessageExt,
     {
         trace!("Lookup request handler for {:?}", get_type!(M));
-        self.routes.get().cloned().or_else(|| self.default.clone().map(Addr::recipient))
+        self.routes.get().cloned()
+            .or_else(|| self.path_routes.get(M::PATH).map(PathRoute::recipient))
+            .or_else(|| self.default.clone().map(Addr::recipient))
 
     }
 
@@ -4,8 +4,7 @@ use ::actix::dev::*;
 use failure::Error;
 use futures::{future, future::Either, Future};
 
-use crate::{app, MessageExt, FutResponse, Routeable, RouteType};
-use super::RouterError;
+use crate::{app, ForwardResponse, MessageExt, Routeable, RouteType};
 
 /// To add a `Future`, the `PendingRoute` wrapper handles a number of tasks:
 /// - Scheduling incoming messages to be handled once the future resolves.
@@ -58,12 +57,13 @@ impl<R, M> Handler<M> for PendingRoute<R>
         R: 'static + Routeable<M>,
         M: MessageExt,
 {
-    type Result = FutResponse<M>;
+    type Result = ForwardResponse<M>;
 
     fn handle(&mut self, msg: M, _ctxt: &mut Context<Self>) -> Self::Result {
         let ty = self.ty;
-        let fut = self.fut.clone()
-                          .map_err(|_| Error::from(RouterError::default()))
+        let (route, ready) = (self.fut.clone(), self.fut.clone());
+        let send = move |msg| route
+                          .map_err(|err| failure::err_msg(err.to_string()))
                           .and_then(move |_| {
                             match ty {
                                 Some(RouteType::Client)   => Either::A(Either::A(app::send_local(msg))),
@@ -72,6 +72,16 @@ impl<R, M> Handler<M> for PendingRoute<R>
                                 None                      => Either::B(Either::B(app::send(msg))),
                             }
                           });
-        FutResponse::from(fut)
+        let notify = move |msg| ready
+                          .map_err(|err| failure::err_msg(err.to_string()))
+                          .and_then(move |_| {
+                            match ty {
+                                Some(RouteType::Client)   => app::notify_local(msg),
+                                Some(RouteType::Server)   => app::notify_in(msg),
+                                Some(RouteType::Upstream) => app::notify_out(msg),
+                                None                      => app::notify(msg),
+                            }
+                          });
+        ForwardResponse::new(msg, send, notify)
     }
-}
\ No newline at end of file
+}
\ No newline at end of file
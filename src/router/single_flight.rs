@@ -0,0 +1,79 @@
+//! Routes which share the response to identical messages sent at the same time.
+
+use ::actix::dev::*;
+use failure::Error;
+use futures::{future::Shared, Future};
+use log::*;
+
+use std::collections::HashMap;
+use std::ops::Deref;
+
+use crate::{FutResponse, MessageExt};
+use super::{IntoMember, Member};
+
+type Flight<M> = Shared<Box<dyn Future<Item=<M as MessageExt>::Response, Error=Error>>>;
+
+/// A route which only sends one of the messages with the same `MessageExt::key`
+/// at a time. Messages arriving while it's in flight get its response too,
+/// so they can't tell it apart from their own.
+///
+/// ```ignore
+/// let app = App::new().route::<Lookup, _>(SingleFlight::new(Remote::from(url)), RouteType::Upstream);
+/// ```
+pub struct SingleFlight<M: MessageExt> {
+    route: Member<M>,
+    /// Each flight is numbered, so a finished one only removes itself.
+    flights: HashMap<Vec<u8>, (u64, Flight<M>)>,
+    next: u64,
+}
+
+impl<M: MessageExt> Clone for SingleFlight<M> {
+    fn clone(&self) -> Self {
+        SingleFlight { route: self.route.clone(), flights: HashMap::new(), next: 0 }
+    }
+}
+
+impl<M: MessageExt> SingleFlight<M> {
+    /// Coalesce the messages sent to `route`.
+    pub fn new<R: IntoMember<M>>(route: R) -> Self {
+        SingleFlight { route: route.into_member(), flights: HashMap::new(), next: 0 }
+    }
+}
+
+impl<M: MessageExt> Actor for SingleFlight<M> {
+    type Context = Context<Self>;
+}
+
+impl<M> Handler<M> for SingleFlight<M>
+    where M: MessageExt,
+          M::Response: Clone,
+{
+    type Result = FutResponse<M>;
+
+    fn handle(&mut self, msg: M, ctxt: &mut Context<Self>) -> Self::Result {
+        let key = match msg.key() {
+            Some(key) => key,
+            None => return FutResponse(self.route.send(msg)),
+        };
+        let flight = match self.flights.get(&key) {
+            Some((_, flight)) => {
+                trace!("Joining the request in flight for {:?}", msg);
+                flight.clone()
+            },
+            None => {
+                let (id, flight) = (self.next, self.route.send(msg).shared());
+                self.next += 1;
+                self.flights.insert(key.clone(), (id, flight.clone()));
+                ctxt.spawn(flight.clone().then(|_| Ok(())).into_actor(self).map(move |(), act, _| {
+                    if act.flights.get(&key).map(|&(current, _)| current) == Some(id) {
+                        act.flights.remove(&key);
+                    }
+                }));
+                flight
+            },
+        };
+        FutResponse::from(flight
+            .map(|resp| resp.deref().clone())
+            .map_err(|err| failure::err_msg(err.to_string())))
+    }
+}
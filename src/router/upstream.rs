@@ -1,9 +1,12 @@
 use actix::prelude::*;
-#[cfg(not(unix))]
 use futures::{Future, IntoFuture};
 use url::Url;
 
-use crate::{http, FutResponse, MessageExt};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{http, limit, ForwardResponse, FutResponse, MessageExt};
+use crate::http::ClientConfig;
 
 impl From<Url> for Remote {
     fn from(other: Url) -> Remote {
@@ -25,8 +28,13 @@ pub enum Remote
     Http(url::Url),
     /// Server located on a local path/socket.
     LocalHttp(std::path::PathBuf),
+    /// Plugin process speaking framed CBOR over its stdin/stdout.
+    #[cfg(unix)]
+    Stdio(crate::plugin::StdioClient),
 }
 
+/// Each message is sent on a connection of its own: route messages
+/// to an `Upstream` instead, which reuses them.
 impl Actor for Remote {
     type Context = Context<Self>;
 }
@@ -34,16 +42,172 @@ impl Actor for Remote {
 impl<M> Handler<M> for Remote
     where M: MessageExt
 {
-    type Result = FutResponse<M>;
+    type Result = ForwardResponse<M>;
     fn handle(&mut self, msg: M, _ctxt: &mut Self::Context) -> Self::Result {
-        log::trace!("Handling remote call to {:?}", self);
-        match self {
-            Remote::Http(url) => FutResponse::from(http::send(&msg, url.clone())),
+        let (upstream, notify) = (Upstream::from(self.clone()), Upstream::from(self.clone()));
+        ForwardResponse::new(msg, move |msg| upstream.send(&msg).0, move |msg| notify.notify(&msg))
+    }
+}
+
+impl Remote {
+    /// Talk to this remote with non-default client options.
+    pub fn with_config(self, config: ClientConfig) -> Upstream {
+        Upstream { remote: self, config, in_flight: Arc::default(), pool: Default::default(), batcher: Default::default() }
+    }
+}
+
+/// The actor forwarding messages to a `Remote`.
+#[derive(Clone, Debug)]
+pub struct Upstream {
+    pub remote: Remote,
+    pub config: ClientConfig,
+    /// Shared between clones, so it counts requests from every actor
+    /// started for this upstream.
+    in_flight: Arc<AtomicUsize>,
+    pool: http::Pool,
+    batcher: http::Batcher,
+}
+
+/// Counts a request (or connection) as in flight until dropped.
+pub(crate) struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    pub(crate) fn new(count: &Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        InFlight(count.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<R: Into<Remote>> From<R> for Upstream {
+    fn from(other: R) -> Upstream {
+        other.into().with_config(ClientConfig::default())
+    }
+}
+
+impl Actor for Upstream {
+    type Context = Context<Self>;
+}
+
+impl Upstream {
+    /// Number of requests sent to the remote which haven't completed yet.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// How the connections to the remote have been used so far.
+    pub fn pool_stats(&self) -> http::PoolStats {
+        self.pool.stats()
+    }
+
+    /// Send `msg` to the remote server.
+    pub(crate) fn send<M>(&self, msg: &M) -> FutResponse<M>
+        where M: MessageExt
+    {
+        log::trace!("Handling remote call to {:?}", self.remote);
+        FutResponse(self.counted(self.send_uncounted(msg, None).0))
+    }
+
+    /// Like `send`, naming `msg` with an idempotency `key`, so the remote can
+    /// tell when it's sent again. It's never batched.
+    /// Plugins talking over stdio don't get the key.
+    pub(crate) fn send_idempotent<M>(&self, msg: &M, key: String) -> FutResponse<M>
+        where M: MessageExt
+    {
+        log::trace!("Handling remote call to {:?} with idempotency key {:?}", self.remote, key);
+        FutResponse(self.counted(self.send_uncounted(msg, Some(key)).0))
+    }
+
+    /// Send `msg` to the remote server, only waiting for it to be accepted.
+    /// Plugins are still waited for, since they always reply.
+    pub(crate) fn notify<M>(&self, msg: &M) -> Box<dyn Future<Item=(), Error=failure::Error>>
+        where M: MessageExt
+    {
+        log::trace!("Notifying {:?}", self.remote);
+        let fut: Box<dyn Future<Item=(), Error=failure::Error>> = match &self.remote {
+            Remote::Http(url) => Box::new(http::notify_pooled(msg, url.clone(), &self.config, &self.pool)),
+            #[cfg(unix)]
+            Remote::LocalHttp(path) => Box::new(http::notify_local_pooled(msg, path, &self.config, &self.pool)),
+            #[cfg(not(unix))]
+            Remote::LocalHttp(_) => Box::new(Err(super::RouterError::default()).into_future().from_err()),
+            #[cfg(unix)]
+            Remote::Stdio(client) => Box::new(client.send(msg).map(|_| ())),
+        };
+        self.counted(fut)
+    }
+
+    /// Count `fut` as in flight, and run it within the limits of the upstream.
+    fn counted<T: 'static>(&self, fut: Box<dyn Future<Item=T, Error=failure::Error>>) -> Box<dyn Future<Item=T, Error=failure::Error>> {
+        let guard = InFlight::new(&self.in_flight);
+        Box::new(limit::limited(self.config.limiter.as_ref(), fut).then(move |res| {
+            drop(guard);
+            res
+        }))
+    }
+
+    fn send_uncounted<M>(&self, msg: &M, key: Option<String>) -> FutResponse<M>
+        where M: MessageExt
+    {
+        if key.is_none() {
+            if let Some(fut) = self.send_batched(msg) {
+                return fut;
+            }
+        }
+        match &self.remote {
+            Remote::Http(url) => FutResponse::from(http::send_pooled(msg, url.clone(), &self.config, &self.pool, key)),
             #[cfg(unix)]
-            Remote::LocalHttp(path) => FutResponse::from(http::send_local(&msg, &path)),
+            Remote::LocalHttp(path) => FutResponse::from(http::send_local_pooled(msg, path, &self.config, &self.pool, key)),
             #[cfg(not(unix))]
             Remote::LocalHttp(_) => FutResponse::from(Err(super::RouterError::default()).into_future().from_err()),
+            #[cfg(unix)]
+            Remote::Stdio(client) => FutResponse::from(client.send(msg)),
             // Remote::LocalRpc(rpc) => FutResponse::from(rpc.send(msg)),
         }
     }
 }
+
+impl Upstream {
+    /// Add `msg` to the next batch, if messages to the remote are batched and
+    /// it doesn't have attachments.
+    fn send_batched<M>(&self, msg: &M) -> Option<FutResponse<M>>
+        where M: MessageExt
+    {
+        #[cfg(unix)]
+        {
+            if let Remote::Stdio(_) = self.remote {
+                return None;
+            }
+        }
+        let config = self.config.batch?;
+        // Attachments are left in place when this fails
+        let body = self.config.encoding.serialize(msg).ok()?;
+        let upstream = self.clone();
+        Some(FutResponse::from(self.batcher.send::<M, _>(body, self.config.encoding, config, move |batch| upstream.send_batch(batch))))
+    }
+
+    fn send_batch(&self, batch: http::Batch) -> Box<dyn Future<Item=Vec<http::Outcome>, Error=failure::Error>> {
+        // Each message of the batch may have a response as large as the limit
+        let config = ClientConfig { limit: self.config.limit.saturating_mul(batch.len()), batch: None, ..self.config.clone() };
+        match &self.remote {
+            Remote::Http(url) => Box::new(http::send_pooled(&batch, url.clone(), &config, &self.pool, None)),
+            #[cfg(unix)]
+            Remote::LocalHttp(path) => Box::new(http::send_local_pooled(&batch, path, &config, &self.pool, None)),
+            _ => Box::new(Err(super::RouterError::default()).into_future().from_err()),
+        }
+    }
+}
+
+impl<M> Handler<M> for Upstream
+    where M: MessageExt
+{
+    type Result = ForwardResponse<M>;
+    fn handle(&mut self, msg: M, _ctxt: &mut Self::Context) -> Self::Result {
+        let (upstream, notify) = (self.clone(), self.clone());
+        ForwardResponse::new(msg, move |msg| upstream.send(&msg).0, move |msg| notify.notify(&msg))
+    }
+}
@@ -0,0 +1,227 @@
+//! Unix sockets used by an `App` to talk to its plugins and local clients.
+//!
+//! Sockets normally live in a directory only accessible to the current user.
+//! On Linux, they can instead be placed in the abstract namespace, which
+//! needs no directory and disappears with the process. Abstract sockets are
+//! written as paths starting with `@`, e.g. `@myapp/main.sock`.
+
+use futures::{future, Future};
+use log::*;
+
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+#[cfg(target_os = "linux")]
+use std::os::raw::{c_char, c_int};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Where an `App` puts its sockets.
+///
+/// Cloning a `SocketDir` shares the directory. The sockets bound through it
+/// are removed once the last clone is dropped, along with the directory
+/// itself if it was created as a temporary one.
+#[derive(Clone, Debug)]
+pub struct SocketDir {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+enum Location {
+    /// A private temporary directory, created on first use.
+    Temp(Mutex<Option<tempfile::TempDir>>),
+    Dir(PathBuf),
+    #[cfg(target_os = "linux")]
+    Abstract(String),
+}
+
+#[derive(Debug)]
+struct Inner {
+    location: Location,
+    /// Sockets to remove on drop.
+    bound: Mutex<Vec<PathBuf>>,
+}
+
+impl Default for SocketDir {
+    fn default() -> Self {
+        SocketDir::temp()
+    }
+}
+
+impl SocketDir {
+    fn with_location(location: Location) -> Self {
+        SocketDir { inner: Arc::new(Inner { location, bound: Mutex::new(Vec::new()) }) }
+    }
+
+    /// A new temporary directory, which is the default.
+    pub fn temp() -> Self {
+        SocketDir::with_location(Location::Temp(Mutex::new(None)))
+    }
+
+    /// Use the directory at `path`. It is created with mode `0700` if it
+    /// doesn't exist, and must not be accessible by other users if it does.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        SocketDir::with_location(Location::Dir(path.into()))
+    }
+
+    /// Name sockets `@<prefix>/<name>.sock` in the abstract namespace.
+    #[cfg(target_os = "linux")]
+    pub fn abstract_namespace(prefix: &str) -> Self {
+        SocketDir::with_location(Location::Abstract(prefix.to_string()))
+    }
+
+    /// The path of the socket called `name`. Fails if the temporary
+    /// directory can't be created.
+    pub fn path(&self, name: &str) -> io::Result<PathBuf> {
+        let file = format!("{}.sock", name);
+        match &self.inner.location {
+            Location::Temp(dir) => {
+                let mut dir = dir.lock().unwrap();
+                if dir.is_none() {
+                    *dir = Some(tempfile::tempdir()?);
+                }
+                Ok(dir.as_ref().unwrap().path().join(file))
+            },
+            Location::Dir(dir) => Ok(dir.join(file)),
+            #[cfg(target_os = "linux")]
+            Location::Abstract(prefix) => Ok(PathBuf::from(format!("@{}/{}", prefix, file))),
+        }
+    }
+
+    /// Bind the socket at `path`, which will be removed when this directory is dropped.
+    pub(crate) fn bind(&self, path: &Path) -> io::Result<tokio_uds::UnixListener> {
+        if let Location::Dir(dir) = &self.inner.location {
+            if path.starts_with(dir) {
+                create_private(dir)?;
+            }
+        }
+        let listener = bind(path)?;
+        self.remove_on_drop(path);
+        Ok(listener)
+    }
+
+    /// Remove the socket at `path` when this directory is dropped,
+    /// for sockets bound by another process.
+    pub(crate) fn remove_on_drop(&self, path: &Path) {
+        if !is_abstract(path) {
+            self.inner.bound.lock().unwrap().push(path.to_path_buf());
+        }
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        for path in self.bound.lock().unwrap().drain(..) {
+            trace!("Removing socket {:?}", path);
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Create `dir` if needed, making sure only the current user can access it.
+fn create_private(dir: &Path) -> io::Result<()> {
+    match fs::DirBuilder::new().recursive(true).mode(0o700).create(dir) {
+        Ok(()) => {},
+        Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {},
+        Err(err) => return Err(err),
+    }
+    if fs::metadata(dir)?.permissions().mode() & 0o077 != 0 {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+            format!("socket directory {:?} is accessible by other users", dir)));
+    }
+    Ok(())
+}
+
+fn is_abstract(path: &Path) -> bool {
+    cfg!(target_os = "linux") && path.as_os_str().as_bytes().first() == Some(&b'@')
+}
+
+/// The address of the abstract socket `path`: its name after the `@`, following a NUL byte.
+#[cfg(target_os = "linux")]
+fn abstract_addr(path: &Path) -> io::Result<(libc::sockaddr_un, libc::socklen_t)> {
+    let name = &path.as_os_str().as_bytes()[1..];
+    let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    if name.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("socket name {:?} is too long", path)));
+    }
+    for (dst, &src) in addr.sun_path[1..].iter_mut().zip(name) {
+        *dst = src as c_char;
+    }
+    let len = std::mem::size_of::<libc::sa_family_t>() + 1 + name.len();
+    Ok((addr, len as libc::socklen_t))
+}
+
+/// A Unix stream socket, set up for the abstract socket `path` by `setup`,
+/// which returns 0 on success like the libc calls it makes.
+#[cfg(target_os = "linux")]
+fn abstract_socket<F>(path: &Path, setup: F) -> io::Result<RawFd>
+    where F: FnOnce(RawFd, *const libc::sockaddr, libc::socklen_t) -> c_int
+{
+    let (addr, len) = abstract_addr(path)?;
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if setup(fd, &addr as *const libc::sockaddr_un as *const libc::sockaddr, len) != 0 {
+        let err = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(err);
+    }
+    Ok(fd)
+}
+
+/// Remove the socket at `path` if nothing is listening on it any more.
+fn remove_stale(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(ref meta) if meta.file_type().is_socket() => {},
+        // Leave anything which isn't a socket for `bind` to fail on
+        _ => return Ok(()),
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AddrInUse,
+            format!("socket {:?} is already in use", path))),
+        Err(_) => {
+            debug!("Removing stale socket {:?}", path);
+            fs::remove_file(path)
+        },
+    }
+}
+
+/// Listen on the socket at `path`, replacing a stale socket left there.
+pub(crate) fn bind(path: &Path) -> io::Result<tokio_uds::UnixListener> {
+    #[cfg(target_os = "linux")]
+    {
+        if is_abstract(path) {
+            let fd = abstract_socket(path, |fd, addr, len| unsafe {
+                match libc::bind(fd, addr, len) {
+                    0 => libc::listen(fd, 128),
+                    err => err,
+                }
+            })?;
+            let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            return tokio_uds::UnixListener::from_std(listener, &tokio_reactor::Handle::default());
+        }
+    }
+    remove_stale(path)?;
+    tokio_uds::UnixListener::bind(path)
+}
+
+/// Connect to the socket at `path`.
+pub(crate) fn connect(path: &Path) -> impl Future<Item=tokio_uds::UnixStream, Error=io::Error> {
+    #[cfg(target_os = "linux")]
+    {
+        if is_abstract(path) {
+            // Connecting to a local socket doesn't block
+            let stream = abstract_socket(path, |fd, addr, len| unsafe { libc::connect(fd, addr, len) })
+                .and_then(|fd| tokio_uds::UnixStream::from_std(unsafe { UnixStream::from_raw_fd(fd) }, &tokio_reactor::Handle::default()));
+            return future::Either::A(future::result(stream));
+        }
+    }
+    future::Either::B::<future::FutureResult<_, _>, _>(tokio_uds::UnixStream::connect(path))
+}
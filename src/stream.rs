@@ -0,0 +1,109 @@
+//! Messages answered with a stream of items, rather than a single response.
+
+use bytes::BytesMut;
+use failure::{err_msg, Error};
+use futures::{Async, Poll, Stream};
+use serde::{de::{self, DeserializeOwned}, ser, Deserialize, Deserializer, Serialize, Serializer};
+
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::http::{attachment, Attachment, PayloadTooLarge};
+use crate::MessageExt;
+
+/// A message whose handler replies with a `MessageStream` of items.
+///
+/// It's routed like any other message. Over HTTP, the items are sent as the
+/// handler produces them, and only as fast as the caller reads them.
+/// Dropping the stream cancels the request.
+pub trait StreamingMessageExt: MessageExt<Response=MessageStream<<Self as StreamingMessageExt>::Item>> {
+    type Item: 'static + Send + DeserializeOwned + Serialize;
+}
+
+type BoxStream<T> = Box<dyn Stream<Item=T, Error=Error> + Send>;
+
+/// The response to a `StreamingMessageExt`.
+///
+/// Like an `Attachment`, it can only be sent once, and only to HTTP remotes or local handlers.
+pub struct MessageStream<T>(Mutex<Option<BoxStream<T>>>);
+
+impl<T> fmt::Debug for MessageStream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MessageStream")
+    }
+}
+
+impl<T: 'static + Send> MessageStream<T> {
+    pub fn new<S>(stream: S) -> Self
+        where S: Stream<Item=T, Error=Error> + Send + 'static
+    {
+        MessageStream(Mutex::new(Some(Box::new(stream))))
+    }
+}
+
+impl<T> Stream for MessageStream<T> {
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<T>, Error> {
+        match self.0.get_mut().unwrap() {
+            Some(stream) => stream.poll(),
+            None => Err(err_msg("stream was already sent")),
+        }
+    }
+}
+
+impl<T: 'static + Send + Serialize> Serialize for MessageStream<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let encoding = attachment::outgoing_encoding()
+            .ok_or_else(|| ser::Error::custom("streams can only be sent over HTTP"))?;
+        let items = self.0.lock().unwrap().take()
+            .ok_or_else(|| ser::Error::custom("stream was already sent"))?;
+        // Each item is length-prefixed, since attachments don't keep their chunks apart
+        let data = items.and_then(move |item| encoding.serialize(&item).map(|item| attachment::frame(&item)));
+        Attachment::new(data).serialize(serializer)
+    }
+}
+
+impl<'de, T: 'static + Send + DeserializeOwned> Deserialize<'de> for MessageStream<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let data = Attachment::deserialize(deserializer)?;
+        let (encoding, limit) = attachment::incoming_encoding()
+            .ok_or_else(|| de::Error::custom("streams can only be received over HTTP"))?;
+        let items = Items { data: data.into_stream(), buf: BytesMut::new(), limit };
+        Ok(MessageStream::new(items.and_then(move |item| encoding.deserialize(&item))))
+    }
+}
+
+/// Splits the data of a stream back into its items.
+struct Items<S> {
+    data: S,
+    buf: BytesMut,
+    /// The largest item accepted.
+    limit: usize,
+}
+
+impl<S: Stream<Item=bytes::Bytes, Error=Error>> Stream for Items<S> {
+    type Item = BytesMut;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<BytesMut>, Error> {
+        loop {
+            if let Some(len) = attachment::frame_len(&self.buf) {
+                if len > self.limit {
+                    return Err(PayloadTooLarge { limit: self.limit }.into());
+                }
+                if self.buf.len() >= 4 + len {
+                    self.buf.advance(4);
+                    return Ok(Async::Ready(Some(self.buf.split_to(len))));
+                }
+            }
+            match self.data.poll()? {
+                Async::Ready(Some(data)) => self.buf.extend_from_slice(&data),
+                Async::Ready(None) if self.buf.is_empty() => return Ok(Async::Ready(None)),
+                Async::Ready(None) => return Err(err_msg("stream is truncated")),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
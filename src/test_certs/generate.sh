@@ -0,0 +1,12 @@
+#!/bin/sh
+# Regenerate the certificates used by `test_https`: a CA, and a certificate
+# it issued for "localhost", valid for a hundred years.
+set -e
+cd "$(dirname "$0")"
+openssl req -x509 -newkey rsa:2048 -nodes -days 36500 -subj "/CN=Test CA" \
+    -addext "basicConstraints=critical,CA:TRUE" -keyout ca.key -out ca.pem
+openssl req -newkey rsa:2048 -nodes -subj "/CN=localhost" -keyout localhost.key -out localhost.csr
+printf "subjectAltName=DNS:localhost\n" > localhost.ext
+openssl x509 -req -in localhost.csr -CA ca.pem -CAkey ca.key -set_serial 1 -days 36500 \
+    -sha256 -extfile localhost.ext -out localhost.pem
+rm ca.key localhost.csr localhost.ext
@@ -1,25 +1,40 @@
+//! Messages and handlers shared by the tests and the `test-plugin` binary,
+//! so that both sides of a plugin agree on the message definitions.
+
 use ::actix::dev::*;
-use futures::Future;
+use futures::{future, stream, Future, Stream};
 use log::*;
 use serde::{Deserialize, Serialize};
 
 use std::path::PathBuf;
 use std::sync::Once;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 static START: Once = Once::new();
 
 use crate::prelude::*;
 
+#[cfg(unix)]
 pub fn test_plugin() -> Plugin {
 	Plugin {
 		name: "test_plugin".to_string(),
-		// version: "0.1"
+		version: "^0.1".to_string(),
+		protocol: crate::plugin::PROTOCOL_VERSION,
 		exec_path: PathBuf::from("./target/debug/test-plugin"),
 		messages: vec![
 			"test".to_string(),
 			"test_empty".to_string(),
 		],
+		paths: vec![
+			TestMessage::PATH.to_string(),
+		],
 		opt_args: Vec::new(),
+		transport: crate::plugin::Transport::Socket,
+		encoding: crate::http::Encoding::Cbor,
+		sandbox: Default::default(),
+		allow: Default::default(),
+		reload: false,
+		handshake_timeout: std::time::Duration::from_secs(10),
 		ty: RouteType::Server,
 	}
 }
@@ -52,6 +67,141 @@ impl MessageExt for TestMessageEmpty {
 	type Response = ();
 }
 
+/// Replied to with the uid of the local process which sent it, if known.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct TestWhoAmI {
+	#[serde(skip)]
+	peer: Option<u32>,
+}
+
+impl Message for TestWhoAmI {
+	type Result = Option<u32>;
+}
+
+impl MessageExt for TestWhoAmI {
+	const PATH: &'static str = "test_whoami";
+
+	type Response = Option<u32>;
+
+	#[cfg(unix)]
+	fn set_peer(&mut self, peer: crate::http::PeerCred) {
+		self.peer = Some(peer.uid);
+	}
+}
+
+/// Sends its attachment back.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TestUpload(pub crate::http::Attachment);
+
+impl Message for TestUpload {
+	type Result = TestUpload;
+}
+
+impl MessageExt for TestUpload {
+	const PATH: &'static str = "test_upload";
+
+	type Response = TestUpload;
+}
+
+/// Counts from 0 up to the given number, or forever.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TestCount(pub Option<u32>);
+
+impl Message for TestCount {
+	type Result = MessageStream<u32>;
+}
+
+impl MessageExt for TestCount {
+	const PATH: &'static str = "test_count";
+
+	type Response = MessageStream<u32>;
+}
+
+impl StreamingMessageExt for TestCount {
+	type Item = u32;
+}
+
+/// The number of `TestCount` streams which haven't been dropped yet.
+pub static TEST_COUNTS: AtomicUsize = AtomicUsize::new(0);
+
+struct CountGuard;
+
+impl Drop for CountGuard {
+	fn drop(&mut self) {
+		TEST_COUNTS.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TestEvent(pub u8);
+
+impl Message for TestEvent {
+	type Result = ();
+}
+
+impl MessageExt for TestEvent {
+	const PATH: &'static str = "test_event";
+
+	type Response = ();
+}
+
+impl EventExt for TestEvent {}
+
+/// The number of `TestEvent`s handled so far.
+pub static TEST_EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TestNote(pub u8);
+
+impl Message for TestNote {
+	type Result = ();
+}
+
+impl MessageExt for TestNote {
+	const PATH: &'static str = "test_note";
+
+	type Response = ();
+}
+
+/// The number of `TestNote`s handled so far.
+pub static TEST_NOTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Replied to after a while, to be sent again while in flight.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TestSlow(pub u8);
+
+impl Message for TestSlow {
+	type Result = u8;
+}
+
+impl MessageExt for TestSlow {
+	const PATH: &'static str = "test_slow";
+
+	type Response = u8;
+}
+
+lazy_static::lazy_static! {
+	/// The number of `TestSlow`s handled so far, by value.
+	pub static ref TEST_SLOWS: Vec<AtomicUsize> = (0..256).map(|_| AtomicUsize::new(0)).collect();
+}
+
+/// Fails for 0, and counts how many times it was handled.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TestLookup(pub u8);
+
+impl Message for TestLookup {
+	type Result = u8;
+}
+
+impl MessageExt for TestLookup {
+	const PATH: &'static str = "test_lookup";
+
+	type Response = u8;
+}
+
+/// The number of `TestLookup`s handled so far.
+pub static TEST_LOOKUPS: AtomicUsize = AtomicUsize::new(0);
+
 #[derive(Default)]
 pub struct TestHandler;
 
@@ -66,7 +216,12 @@ impl Service for TestHandler {
 		   .route::<TestMessage, _>(addr.clone(), RouteType::Server)
 		   .route::<TestMessageEmpty, _>(addr.clone(), RouteType::Server)
 		   .expose::<TestMessage>()
+		   .route::<TestWhoAmI, _>(addr.clone(), RouteType::Server)
+		   .expose::<TestWhoAmI>()
 		   .route(("test", addr.clone()), RouteType::Server)
+		   .route(("callback", addr.clone()), RouteType::Server)
+		   .route(("pid", addr.clone()), RouteType::Server)
+		   .route(("sandbox", addr.clone()), RouteType::Server)
 	}
 }
 
@@ -79,22 +234,117 @@ impl Handler<TestMessage> for TestHandler {
 	}
 }
 
+impl Handler<TestWhoAmI> for TestHandler {
+	type Result = MessageResult<TestWhoAmI>;
+
+	fn handle(&mut self, msg: TestWhoAmI, _ctxt: &mut Context<Self>) -> Self::Result {
+		MessageResult(msg.peer)
+	}
+}
+
+impl Handler<TestUpload> for TestHandler {
+	type Result = FutResponse<TestUpload>;
+
+	fn handle(&mut self, msg: TestUpload, _ctxt: &mut Context<Self>) -> Self::Result {
+		FutResponse::from(msg.0.concat().map(|data| TestUpload(crate::http::Attachment::from_bytes(data))))
+	}
+}
+
+impl Handler<TestCount> for TestHandler {
+	type Result = MessageResult<TestCount>;
+
+	fn handle(&mut self, msg: TestCount, _ctxt: &mut Context<Self>) -> Self::Result {
+		TEST_COUNTS.fetch_add(1, Ordering::SeqCst);
+		let guard = CountGuard;
+		let count = stream::iter_ok(0..msg.0.unwrap_or(u32::MAX)).map(move |i| {
+			let _ = &guard;
+			i
+		});
+		MessageResult(MessageStream::new(count))
+	}
+}
+
+impl Handler<TestEvent> for TestHandler {
+	type Result = ();
+
+	fn handle(&mut self, _msg: TestEvent, _ctxt: &mut Context<Self>) {
+		TEST_EVENTS.fetch_add(1, Ordering::SeqCst);
+	}
+}
+
+impl Handler<TestNote> for TestHandler {
+	type Result = ();
+
+	fn handle(&mut self, _msg: TestNote, _ctxt: &mut Context<Self>) {
+		TEST_NOTES.fetch_add(1, Ordering::SeqCst);
+	}
+}
+
+impl Handler<TestSlow> for TestHandler {
+	type Result = FutResponse<TestSlow>;
+
+	fn handle(&mut self, msg: TestSlow, _ctxt: &mut Context<Self>) -> Self::Result {
+		TEST_SLOWS[msg.0 as usize].fetch_add(1, Ordering::SeqCst);
+		let delay = tokio::timer::Delay::new(std::time::Instant::now() + std::time::Duration::from_millis(50));
+		FutResponse::from(delay.map(move |_| msg.0).map_err(failure::Error::from))
+	}
+}
+
+impl Handler<TestLookup> for TestHandler {
+	type Result = FutResponse<TestLookup>;
+
+	fn handle(&mut self, msg: TestLookup, _ctxt: &mut Context<Self>) -> Self::Result {
+		TEST_LOOKUPS.fetch_add(1, Ordering::SeqCst);
+		match msg.0 {
+			0 => FutResponse::from(future::err(failure::err_msg("not found"))),
+			n => FutResponse::from(future::ok(n)),
+		}
+	}
+}
 
 impl Handler<OpaqueMessage> for TestHandler {
-	type Result = MessageResult<OpaqueMessage>;
+	type Result = FutResponse<OpaqueMessage>;
 
 	fn handle(&mut self, msg: OpaqueMessage, _ctxt: &mut Context<Self>) -> Self::Result {
 		trace!("Handling TestMessage from TestHandler");
-		if msg.id == "test" {
-			MessageResult(OpaqueMessage {
+		match msg.id.as_str() {
+			"test" => FutResponse::from(future::ok(OpaqueMessage {
 				id: "test_response".to_string(),
 				inner: b"some reply".to_vec(),
-			})
-		} else {
-			MessageResult(OpaqueMessage {
+			})),
+			// Pass the inner `TestMessage` upstream, i.e. to the host when running as a plugin
+			"callback" => FutResponse::from(future::result(msg.inner::<TestMessage>())
+				.and_then(app::send_out)
+				.and_then(|resp| OpaqueMessage::try_new("callback_response", resp))),
+			// Tells which process handled the message
+			"pid" => FutResponse::from(future::result(OpaqueMessage::try_new("pid_response", std::process::id()))),
+			// Tells what the process sees of its sandbox
+			"sandbox" => FutResponse::from(future::result(OpaqueMessage::try_new("sandbox_response", SandboxReport::current()))),
+			_ => FutResponse::from(future::ok(OpaqueMessage {
 				id: "err".to_string(),
 				inner: b"unknown route".to_vec(),
-			})
+			})),
+		}
+	}
+}
+
+/// What a process can see of the sandbox it runs in.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SandboxReport {
+	pub open_files: u64,
+	/// The names of its environment variables.
+	pub env: Vec<String>,
+	pub working_dir: PathBuf,
+}
+
+impl SandboxReport {
+	fn current() -> Self {
+		let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+		unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) };
+		SandboxReport {
+			open_files: limit.rlim_cur as u64,
+			env: std::env::vars_os().map(|(key, _)| key.to_string_lossy().into_owned()).collect(),
+			working_dir: std::env::current_dir().unwrap_or_default(),
 		}
 	}
 }
@@ -0,0 +1,65 @@
+#!/usr/bin/env python3
+"""A plugin speaking the actix-directory plugin protocol with JSON bodies,
+used to test that plugins need not be written in Rust."""
+
+import json
+import os
+import socketserver
+import sys
+import threading
+import time
+from http.server import BaseHTTPRequestHandler
+
+VERSION = "0.1.0"
+PROTOCOL = 1
+
+
+class Handler(BaseHTTPRequestHandler):
+    protocol_version = "HTTP/1.1"
+
+    def do_POST(self):
+        length = int(self.headers.get("Content-Length", 0))
+        body = json.loads(self.rfile.read(length))
+        if self.path == "/_plugin/handshake":
+            self.reply(200, {"version": VERSION, "protocol": PROTOCOL})
+        elif self.path == "/" and body["id"] == "echo":
+            self.reply(200, {"id": "echo_response", "inner": body["inner"]})
+        else:
+            self.reply(500, {"error": "unknown message: {}".format(body.get("id", self.path))})
+
+    def reply(self, status, value):
+        data = json.dumps(value).encode()
+        self.send_response(status)
+        self.send_header("Content-Type", "application/json")
+        self.send_header("Content-Length", str(len(data)))
+        self.end_headers()
+        self.wfile.write(data)
+
+    def log_message(self, fmt, *args):
+        sys.stderr.write("[test_plugin.py] " + (fmt % args) + "\n")
+
+
+class Server(socketserver.ThreadingMixIn, socketserver.UnixStreamServer):
+    daemon_threads = True
+
+
+def exit_with_parent():
+    """Exit once the host goes away, since nothing else stops the server."""
+    parent = os.getppid()
+    while os.getppid() == parent:
+        time.sleep(0.2)
+    os._exit(0)
+
+
+def main():
+    if os.environ.get("ACTIX_DIRECTORY_ENCODING") != "json":
+        sys.exit("this plugin only speaks JSON")
+    threading.Thread(target=exit_with_parent, daemon=True).start()
+    sock = sys.argv[2]
+    if os.path.exists(sock):
+        os.remove(sock)
+    Server(sock, Handler).serve_forever()
+
+
+if __name__ == "__main__":
+    main()